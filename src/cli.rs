@@ -1,14 +1,34 @@
 use clap::value_parser;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 /// Multi-Pattern Streams Detector
 #[derive(Debug, Parser)]
 #[command(long_about = None)]
 pub struct Cli {
-    /// Enable WAV (RIFF WAVE PCM) detection
+    /// Enable WAV (RIFF WAVE) detection
     #[arg(long = "wav", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
     pub detect_wav: u8,
 
+    /// Enable RF64/BW64 (64-bit WAVE) detection
+    #[arg(long = "rf64", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_rf64: u8,
+
+    /// Enable Sony Wave64 detection
+    #[arg(long = "w64", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_w64: u8,
+
+    /// Enable RIFX (big-endian WAVE) detection
+    #[arg(long = "rifx", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_rifx: u8,
+
+    /// Enable AIFF/AIFC detection
+    #[arg(long = "aiff", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_aiff: u8,
+
+    /// Enable other RIFF (AVI, WebP, RMI, ANI, DLS, CDXA) detection
+    #[arg(long = "riff", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_riff: u8,
+
     /// Enable OGG detection
     #[arg(long = "ogg", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
     pub detect_ogg: u8,
@@ -17,6 +37,22 @@ pub struct Cli {
     #[arg(long = "bmp", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
     pub detect_bmp: u8,
 
+    /// Enable PNG detection
+    #[arg(long = "png", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_png: u8,
+
+    /// Enable JPEG (JFIF/EXIF) detection
+    #[arg(long = "jpeg", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_jpeg: u8,
+
+    /// Enable FLAC detection
+    #[arg(long = "flac", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_flac: u8,
+
+    /// Enable ISO-BMFF (MP4/MOV/3GP/HEIF) detection
+    #[arg(long = "mp4", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
+    pub detect_mp4: u8,
+
     /// Enable AAC (ADTS) detection
     #[arg(long = "aac", global = true, value_parser = value_parser!(u8).range(0..=1), default_value_t = 1)]
     pub detect_aac: u8,
@@ -33,9 +69,26 @@ pub struct Cli {
     #[arg(long = "mpeg-max-frames", global = true, default_value_t = 10000)]
     pub mpeg_max_frames: u16,
 
+    /// BMP validation level (0 = header only, 1 = plausible fields and file size, 2 = consistent sizes)
+    #[arg(long = "bmp-strict", global = true, value_parser = value_parser!(u8).range(0..=2), default_value_t = 1)]
+    pub bmp_strict: u8,
+
+    /// Number of detection threads (0 = number of CPUs)
+    #[arg(long = "threads", global = true, default_value_t = 0)]
+    pub threads: usize,
+
+    /// Size of the chunks (MiB) the input is split into for the threads
+    #[arg(long = "chunk-size", global = true, value_parser = value_parser!(u16).range(1..), default_value_t = 16)]
+    pub chunk_size: u16,
+
+    /// Read window (MiB) for inputs that cannot be memory-mapped (stdin, pipes)
+    /// Streams longer than the window are cut
+    #[arg(long = "window-size", global = true, value_parser = value_parser!(u16).range(1..), default_value_t = 64, verbatim_doc_comment)]
+    pub window_size: u16,
+
     /// Replace the found sectors in the input file with zeros
-    /// WARNING: backup the input file to prevent data loss
-    /// Could be enabled only in "Extract" mode
+    /// An undo journal (<input>.mpsd-journal) is written first, see "unerase"
+    /// In "Scan" mode the journal keeps the original bytes
     #[arg(long = "erase-sectors", global = true, value_parser = value_parser!(bool), default_value_t = false, verbatim_doc_comment)]
     pub erase_sectors: bool,
 
@@ -57,17 +110,25 @@ pub enum Commands {
     /// Scan the input file
     #[command(arg_required_else_help = true)]
     Scan {
-        /// Path to the input file
+        /// Path to the input file ("-" for stdin)
         file_path: String,
+
+        /// Output format
+        #[arg(long = "format", value_enum, default_value_t = Format::Text)]
+        format: Format,
     },
     /// Extract streams from the input file
     #[command(arg_required_else_help = true)]
     Extract {
-        /// Path to the input file
+        /// Path to the input file ("-" for stdin)
         file_path: String,
 
         /// Path to the output folder (for extracted files)
         output_dir: String,
+
+        /// Output format
+        #[arg(long = "format", value_enum, default_value_t = Format::Text)]
+        format: Format,
     },
     /// Inject streams back to the input file from the given folder
     /// (supports large files on 32-bit systems)
@@ -76,12 +137,44 @@ pub enum Commands {
         /// Path to the input file
         file_path: String,
 
-        /// Path to the input folder (with extracted files)
+        /// Path to the input folder (with extracted files and manifest.json)
         input_dir: String,
     },
+    /// Restore the erased sectors of the input file from its journal
+    #[command(arg_required_else_help = true)]
+    Unerase {
+        /// Path to the input file
+        file_path: String,
+
+        /// Path to the folder with extracted files
+        /// (defaults to the one recorded in the journal)
+        #[arg(verbatim_doc_comment)]
+        input_dir: Option<String>,
+    },
+    /// Verify that the extracted streams match the input file
+    /// (and that the file was fully restored after erasing)
+    #[command(arg_required_else_help = true)]
+    Verify {
+        /// Path to the input file
+        file_path: String,
+
+        /// Path to the input folder (with extracted files and manifest.json)
+        input_dir: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Human-readable log
+    Text,
+    /// Single JSON document with all streams and the summary
+    Json,
+    /// One JSON record per line (streams, then the summary)
+    Jsonl,
+    /// CSV with a header row (streams, then the summary)
+    Csv,
 }
 
 pub fn parse() -> Cli {
-    let args = Cli::parse();
-    return args;
+    Cli::parse()
 }
@@ -1,35 +1,218 @@
 pub mod aac;
+pub mod aiff;
 pub mod bitmap;
+mod bytes;
+mod crc;
+pub mod flac;
+pub mod iso_bmff;
+pub mod jpeg;
 pub mod mp3;
 pub mod ogg;
+pub mod png;
+pub mod riff;
 pub mod riff_wave;
+pub mod tags;
 
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy)]
 pub struct DetectOptions {
     pub mpeg_min_frames: u8,
     pub mpeg_max_frames: u16,
+    /// BMP validation level (0 = header layout only, 1 = plausible fields,
+    /// 2 = consistent sizes and field combinations)
+    pub bmp_strict: u8,
+}
+
+impl Default for DetectOptions {
+    fn default() -> Self {
+        DetectOptions {
+            mpeg_min_frames: 20,
+            mpeg_max_frames: 10000,
+            bmp_strict: 1,
+        }
+    }
 }
 
-pub struct StreamMatch<'a> {
+/// Detector-specific value describing a stream (e.g. sample rate).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum MetaValue {
+    Int(u64),
+    Bool(bool),
+    Text(String),
+}
+
+pub type Metadata = BTreeMap<&'static str, MetaValue>;
+
+#[derive(Debug, Clone)]
+pub struct StreamMatch {
     pub offset: usize,
     pub size: usize,
-    pub ext: &'a str,
+    pub ext: &'static str,
+    pub stream_type: StreamType,
+    pub metadata: Metadata,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl From<u64> for MetaValue {
+    fn from(value: u64) -> Self {
+        MetaValue::Int(value)
+    }
+}
+
+impl From<u32> for MetaValue {
+    fn from(value: u32) -> Self {
+        MetaValue::Int(value.into())
+    }
+}
+
+impl From<u16> for MetaValue {
+    fn from(value: u16) -> Self {
+        MetaValue::Int(value.into())
+    }
+}
+
+impl From<usize> for MetaValue {
+    fn from(value: usize) -> Self {
+        MetaValue::Int(value as u64)
+    }
+}
+
+impl From<bool> for MetaValue {
+    fn from(value: bool) -> Self {
+        MetaValue::Bool(value)
+    }
+}
+
+impl From<&str> for MetaValue {
+    fn from(value: &str) -> Self {
+        MetaValue::Text(value.to_string())
+    }
+}
+
+impl std::fmt::Display for MetaValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetaValue::Int(value) => write!(f, "{}", value),
+            MetaValue::Bool(value) => write!(f, "{}", value),
+            MetaValue::Text(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum StreamType {
     RiffWave,
+    Rf64,
+    Wave64,
+    Rifx,
+    Aiff,
+    Riff,
     Bitmap,
     Ogg,
+    Png,
+    Jpeg,
+    Flac,
+    IsoBmff,
     Aac,
     Mp3,
 }
 
-pub trait Detector {
+impl StreamType {
+    /// All supported stream types. Types sharing a signature are tried
+    /// in this order.
+    pub const ALL: [StreamType; 14] = [
+        StreamType::RiffWave,
+        StreamType::Rf64,
+        StreamType::Wave64,
+        StreamType::Rifx,
+        StreamType::Aiff,
+        StreamType::Riff,
+        StreamType::Bitmap,
+        StreamType::Ogg,
+        StreamType::Png,
+        StreamType::Jpeg,
+        StreamType::Flac,
+        StreamType::IsoBmff,
+        StreamType::Aac,
+        StreamType::Mp3,
+    ];
+
+    /// Byte patterns the detector is triggered by (the pattern starts
+    /// `signature_offset()` bytes after the stream offset).
+    pub fn signatures(self) -> &'static [&'static [u8]] {
+        match self {
+            StreamType::RiffWave | StreamType::Riff => &[riff::SIGNATURE],
+            StreamType::Rf64 => riff::RF64_SIGNATURES,
+            StreamType::Wave64 => &[riff::WAVE64_SIGNATURE],
+            StreamType::Rifx => &[riff::RIFX_SIGNATURE],
+            StreamType::Aiff => &[riff::IFF_SIGNATURE],
+            StreamType::Bitmap => &[b"BM"],
+            StreamType::Ogg => &[b"OggS"],
+            StreamType::Png => &[png::SIGNATURE],
+            StreamType::Jpeg => &[jpeg::SIGNATURE],
+            StreamType::Flac => &[flac::SIGNATURE],
+            StreamType::IsoBmff => &[iso_bmff::SIGNATURE],
+            StreamType::Aac => &[b"\xFF"],
+            StreamType::Mp3 => &[b"\xFF", tags::ID3V2_SIGNATURE],
+        }
+    }
+
+    /// Distance from the stream start to the signature.
+    pub fn signature_offset(self) -> usize {
+        match self {
+            StreamType::IsoBmff => iso_bmff::SIGNATURE_OFFSET,
+            _ => 0,
+        }
+    }
+
+    pub fn detector(self) -> &'static dyn Detector {
+        match self {
+            StreamType::RiffWave => &RiffWaveDetector,
+            StreamType::Rf64 => &Rf64Detector,
+            StreamType::Wave64 => &Wave64Detector,
+            StreamType::Rifx => &RifxDetector,
+            StreamType::Aiff => &AiffDetector,
+            StreamType::Riff => &RiffDetector,
+            StreamType::Bitmap => &BitmapDetector,
+            StreamType::Ogg => &OggDetector,
+            StreamType::Png => &PngDetector,
+            StreamType::Jpeg => &JpegDetector,
+            StreamType::Flac => &FlacDetector,
+            StreamType::IsoBmff => &IsoBmffDetector,
+            StreamType::Aac => &AacDetector,
+            StreamType::Mp3 => &Mp3Detector,
+        }
+    }
+}
+
+/// End of a stream whose closing chunk or marker (PNG `IEND`, JPEG `EOI`,
+/// AIFF `SSND`) ends at `end`. A stream where it is missing only counts
+/// when it was cut by the end of the buffer, and then ends there.
+fn end_or_cut(buffer: &[u8], end: Option<usize>, is_cut: bool) -> Option<usize> {
+    match end {
+        Some(end) => Some(end),
+        None if is_cut => Some(buffer.len()),
+        None => None,
+    }
+}
+
+pub trait Detector: Sync {
     fn detect(&self, buffer: &[u8], offset: usize, opts: &DetectOptions) -> Option<StreamMatch>;
 }
 
 pub struct RiffWaveDetector;
+pub struct Rf64Detector;
+pub struct Wave64Detector;
+pub struct RifxDetector;
+pub struct AiffDetector;
+pub struct RiffDetector;
 pub struct BitmapDetector;
 pub struct OggDetector;
+pub struct PngDetector;
+pub struct JpegDetector;
+pub struct FlacDetector;
+pub struct IsoBmffDetector;
 pub struct AacDetector;
 pub struct Mp3Detector;
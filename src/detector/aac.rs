@@ -1,4 +1,4 @@
-use super::{AacDetector, DetectOptions, Detector, StreamMatch};
+use super::{AacDetector, DetectOptions, Detector, Metadata, StreamMatch, StreamType};
 
 fn is_valid_frame_header(bytes: &[u8]) -> bool {
     bytes[0] == 0xFF
@@ -21,7 +21,7 @@ fn parse_frame_length(bytes: &[u8]) -> u16 {
     frame_length |= (bytes[4] as u16) << 3;
     frame_length |= ((bytes[5] & 0xE0) >> 5) as u16;
 
-    return frame_length;
+    frame_length
 }
 
 impl Detector for AacDetector {
@@ -65,9 +65,11 @@ impl Detector for AacDetector {
                 offset,
                 size,
                 ext: "aac",
+                stream_type: StreamType::Aac,
+                metadata: Metadata::from([("frames", frames.into())]),
             });
         }
 
-        return None;
+        None
     }
 }
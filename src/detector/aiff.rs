@@ -0,0 +1,231 @@
+use super::bytes::{read_u16_be, read_u32_be, read_u64_be};
+use super::riff::Riff;
+use super::riff_wave::pcm_frame_size;
+use super::{end_or_cut, AiffDetector, DetectOptions, Detector, Metadata, StreamMatch, StreamType};
+
+/// Channels (2) + sample frames (4) + sample size (2) + sample rate (10)
+const COMM_SIZE: usize = 18;
+
+/// Offset (4) + block size (4) before the sound data
+const SSND_HEADER_SIZE: usize = 8;
+
+/// Highest plausible sample rate
+const MAX_SAMPLE_RATE: u64 = 1_000_000;
+
+/// AIFC compression types of uncompressed audio
+const UNCOMPRESSED: &[&[u8; 4]] = &[
+    b"NONE", b"sowt", b"twos", b"raw ", b"in24", b"in32", b"fl32", b"FL32", b"fl64", b"FL64",
+];
+
+/// Integer value of an 80-bit IEEE 754 extended precision number (the
+/// sample rate in `COMM`), or `None` if it is negative, below 1 or too
+/// large to be a sample rate.
+fn read_extended(bytes: &[u8]) -> Option<u64> {
+    let exponent = read_u16_be(bytes, 0);
+    let mantissa = read_u64_be(bytes, 2);
+
+    // Sign bit set, or value below 1
+    if exponent & 0x8000 != 0 || exponent < 16383 {
+        return None;
+    }
+
+    let shift = 63u16.checked_sub(exponent - 16383)?;
+
+    Some(mantissa >> shift)
+}
+
+impl Detector for AiffDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        let riff = Riff::parse_iff(buffer, offset)?;
+
+        let (ext, compressed) = match &riff.form {
+            b"AIFF" => ("aif", false),
+            b"AIFC" => ("aifc", true),
+            _ => return None,
+        };
+
+        let comm = riff.chunks.iter().find(|x| &x.id == b"COMM")?;
+        let comm_data = buffer.get(comm.offset..comm.offset + comm.size)?;
+
+        if comm_data.len() < COMM_SIZE + if compressed { 4 } else { 0 } {
+            return None;
+        }
+
+        let channels = read_u16_be(comm_data, 0);
+        let sample_frames = read_u32_be(comm_data, 2);
+        let bits_per_sample = read_u16_be(comm_data, 6);
+        let sample_rate = read_extended(&comm_data[8..18])?;
+
+        let compression = if compressed {
+            &comm_data[COMM_SIZE..COMM_SIZE + 4]
+        } else {
+            b"NONE"
+        };
+
+        if channels == 0
+            || !(1..=32).contains(&bits_per_sample)
+            || !(1..=MAX_SAMPLE_RATE).contains(&sample_rate)
+        {
+            return None;
+        }
+
+        let ssnd = riff.chunks.iter().find(|x| &x.id == b"SSND");
+
+        // The sound data has to hold all sample frames
+        if let Some(ssnd) = ssnd.filter(|_| UNCOMPRESSED.iter().any(|x| *x == compression)) {
+            let frame_size = pcm_frame_size(channels, bits_per_sample);
+            let data_size = u64::from(sample_frames) * u64::from(frame_size);
+
+            if (ssnd.size as u64) < SSND_HEADER_SIZE as u64 + data_size {
+                return None;
+            }
+        }
+
+        // An empty file needs no SSND
+        let end = (ssnd.is_some() || sample_frames == 0).then_some(riff.end.min(buffer.len()));
+        let size = end_or_cut(buffer, end, riff.end > buffer.len())? - offset;
+
+        let mut metadata = Metadata::from([
+            ("channels", channels.into()),
+            ("sample_rate", sample_rate.into()),
+            ("bits_per_sample", bits_per_sample.into()),
+            ("sample_frames", sample_frames.into()),
+        ]);
+
+        if compressed {
+            metadata.insert(
+                "compression",
+                String::from_utf8_lossy(compression).as_ref().into(),
+            );
+        }
+
+        Some(StreamMatch {
+            offset,
+            size,
+            ext,
+            stream_type: StreamType::Aiff,
+            metadata,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::riff::tests::{chunk_be, riff_be};
+    use crate::detector::MetaValue;
+
+    fn form(form_type: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        riff_be(b"FORM", form_type, chunks)
+    }
+
+    /// 80-bit extended precision value of a whole number.
+    fn extended(value: u64) -> Vec<u8> {
+        let shift = value.leading_zeros();
+        let exponent = 16383 + 63 - shift as u16;
+
+        [
+            exponent.to_be_bytes().as_slice(),
+            &(value << shift).to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn comm(
+        channels: u16,
+        sample_frames: u32,
+        bits_per_sample: u16,
+        compression: &[u8],
+    ) -> Vec<u8> {
+        let data = [
+            channels.to_be_bytes().as_slice(),
+            &sample_frames.to_be_bytes(),
+            &bits_per_sample.to_be_bytes(),
+            &extended(44100),
+            compression,
+        ]
+        .concat();
+
+        chunk_be(b"COMM", &data)
+    }
+
+    fn ssnd(data_size: usize) -> Vec<u8> {
+        chunk_be(
+            b"SSND",
+            &[vec![0; SSND_HEADER_SIZE], vec![0x55; data_size]].concat(),
+        )
+    }
+
+    #[test]
+    fn sample_rates_are_read() {
+        assert_eq!(read_extended(&extended(44100)), Some(44100));
+        assert_eq!(read_extended(&extended(8000)), Some(8000));
+        assert_eq!(read_extended(&extended(1)), Some(1));
+
+        // Negative and below 1
+        assert_eq!(
+            read_extended(&[0xC0, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+        assert_eq!(
+            read_extended(&[0x3F, 0xFE, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn aiff_and_aifc() {
+        let opts = DetectOptions::default();
+        let file = form(b"AIFF", &[comm(2, 250, 16, &[]), ssnd(1000)]);
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = AiffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "aif");
+        assert_eq!(m.metadata["channels"], MetaValue::Int(2));
+        assert_eq!(m.metadata["sample_rate"], MetaValue::Int(44100));
+        assert_eq!(m.metadata["bits_per_sample"], MetaValue::Int(16));
+        assert_eq!(m.metadata["sample_frames"], MetaValue::Int(250));
+
+        let fver = chunk_be(b"FVER", &0xA280_5140u32.to_be_bytes());
+        let file = form(b"AIFC", &[fver, comm(1, 500, 16, b"sowt\0"), ssnd(1000)]);
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = AiffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "aifc");
+        assert_eq!(m.metadata["compression"], MetaValue::from("sowt"));
+
+        // An empty file without SSND
+        let file = form(b"AIFF", &[comm(2, 0, 16, &[])]);
+        assert_eq!(
+            AiffDetector.detect(&file, 0, &opts).unwrap().size,
+            file.len()
+        );
+
+        // A file cut in SSND ends at the end of the buffer
+        let file = form(b"AIFF", &[comm(2, 250, 16, &[]), ssnd(1000)]);
+        let m = AiffDetector.detect(&file[..500], 0, &opts).unwrap();
+        assert_eq!(m.size, 500);
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        let opts = DetectOptions::default();
+        let invalid = [
+            // SSND smaller than the sample frames
+            form(b"AIFF", &[comm(2, 251, 16, &[]), ssnd(1000)]),
+            // No SSND
+            form(b"AIFF", &[comm(2, 250, 16, &[])]),
+            // No channels
+            form(b"AIFF", &[comm(0, 250, 16, &[]), ssnd(1000)]),
+            // Another form type
+            form(b"8SVX", &[comm(2, 250, 16, &[]), ssnd(1000)]),
+        ];
+
+        for file in invalid {
+            let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+            assert!(AiffDetector.detect(&buffer, 0, &opts).is_none());
+        }
+    }
+}
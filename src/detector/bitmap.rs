@@ -1,16 +1,61 @@
-use super::{BitmapDetector, DetectOptions, Detector, StreamMatch};
+use super::bytes::{read_struct, read_u32_le};
+use super::{BitmapDetector, DetectOptions, Detector, Metadata, StreamMatch, StreamType};
+
+const FILE_HEADER_SIZE: usize = 14;
+
+/// OS/2 1.x BITMAPCOREHEADER
+const CORE_HEADER_SIZE: usize = 12;
+/// BITMAPINFOHEADER
+const INFO_HEADER_SIZE: usize = 40;
+/// BITMAPV5HEADER, the largest one
+const V5_HEADER_SIZE: usize = 124;
+
+/// Sizes of the DIB headers that share the BITMAPINFOHEADER layout (OS/2
+/// 2.x short and full header, BITMAPINFOHEADER, V2, V3, V4 and V5)
+const INFO_HEADER_SIZES: &[usize] = &[16, 40, 52, 56, 64, 108, 124];
+
+/// Bytes some writers add after the pixel data (and count in the file size)
+const MAX_TRAILING_BYTES: usize = 4;
+
+/// Largest plausible width and height
+const MAX_DIMENSION: u32 = 0xFFFF;
+
+const VALID_BPP: &[u16] = &[1, 4, 8, 16, 24, 32];
+
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+const BI_JPEG: u32 = 4;
+const BI_PNG: u32 = 5;
+const BI_ALPHABITFIELDS: u32 = 6;
 
 #[repr(C, packed)]
-#[derive(Debug, Default)]
-struct BitmapHeader {
+#[derive(Debug, Clone, Copy, Default)]
+struct BitmapFileHeader {
     id: [u8; 2],
     size: u32,
     reserved1: u16,
     reserved2: u16,
     offset: u32,
-    id2: u32,
-    width: u32,
-    height: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+struct BitmapCoreHeader {
+    header_size: u32,
+    width: u16,
+    height: u16,
+    planes: u16,
+    bpp: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+struct BitmapInfoHeader {
+    header_size: u32,
+    width: i32,
+    height: i32,
     planes: u16,
     bpp: u16,
     compression: u32,
@@ -21,44 +66,424 @@ struct BitmapHeader {
     colors_important: u32,
 }
 
-impl Detector for BitmapDetector {
-    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
-        if offset + std::mem::size_of::<BitmapHeader>() > buffer.len() {
+/// Fields of any DIB header version that are needed to lay out the file.
+#[derive(Debug, Clone, Copy)]
+struct Dib {
+    header_size: usize,
+    width: u32,
+    height: u32,
+    planes: u16,
+    bpp: u16,
+    compression: u32,
+    size_image: usize,
+    colors_used: usize,
+    /// Size of the color table
+    palette_size: usize,
+    /// Size of the color masks stored after a BITMAPINFOHEADER
+    masks_size: usize,
+    /// End of the embedded ICC profile (V5), relative to the DIB header
+    profile_end: usize,
+}
+
+impl Dib {
+    fn parse(data: &[u8]) -> Option<Dib> {
+        let header_size = read_u32_le(data.get(..4)?, 0) as usize;
+
+        if header_size == CORE_HEADER_SIZE {
+            let header: BitmapCoreHeader = read_struct(data)?;
+
+            return Some(Dib {
+                header_size,
+                width: header.width.into(),
+                height: header.height.into(),
+                planes: header.planes,
+                bpp: header.bpp,
+                compression: BI_RGB,
+                size_image: 0,
+                colors_used: 0,
+                // RGBTRIPLE entries
+                palette_size: if header.bpp <= 8 { 3 << header.bpp } else { 0 },
+                masks_size: 0,
+                profile_end: 0,
+            });
+        }
+
+        if !INFO_HEADER_SIZES.contains(&header_size) {
             return None;
         }
 
-        let (head, body, _tail) = unsafe {
-            &buffer[offset..offset + std::mem::size_of::<BitmapHeader>()].align_to::<BitmapHeader>()
+        // Short OS/2 2.x headers end after the bit count; the missing
+        // fields are zero
+        let mut bytes = [0; INFO_HEADER_SIZE];
+        let len = header_size.min(INFO_HEADER_SIZE);
+        bytes[..len].copy_from_slice(data.get(..len)?);
+
+        let header: BitmapInfoHeader = read_struct(&bytes)?;
+        let colors_used = usize::try_from(header.colors_used).ok()?;
+        let colors = match colors_used {
+            0 if header.bpp <= 8 => 1 << header.bpp,
+            x => x,
         };
 
-        if !head.is_empty() {
-            return None;
+        let masks_size = match (header_size, header.compression) {
+            (INFO_HEADER_SIZE, BI_BITFIELDS) => 12,
+            (INFO_HEADER_SIZE, BI_ALPHABITFIELDS) => 16,
+            _ => 0,
+        };
+
+        let profile_end = if header_size == V5_HEADER_SIZE {
+            let v5 = data.get(..V5_HEADER_SIZE)?;
+            (read_u32_le(v5, 112) as usize).saturating_add(read_u32_le(v5, 116) as usize)
+        } else {
+            0
+        };
+
+        Some(Dib {
+            header_size,
+            // Negative heights are top-down images
+            width: header.width.unsigned_abs(),
+            height: header.height.unsigned_abs(),
+            planes: header.planes,
+            bpp: header.bpp,
+            compression: header.compression,
+            size_image: usize::try_from(header.size_image).ok()?,
+            colors_used,
+            // RGBQUAD entries
+            palette_size: colors.checked_mul(4)?,
+            masks_size,
+            profile_end,
+        })
+    }
+
+    /// Size of the pixel data. Uncompressed rows are padded to 4 bytes;
+    /// compressed data has its size in the header.
+    fn image_size(&self) -> Option<usize> {
+        match self.compression {
+            BI_RGB | BI_BITFIELDS | BI_ALPHABITFIELDS => {
+                let row_bits = u64::from(self.width) * u64::from(self.bpp);
+                let stride = row_bits.div_ceil(32) * 4;
+                usize::try_from(stride.checked_mul(u64::from(self.height))?).ok()
+            }
+            _ if self.size_image > 0 => Some(self.size_image),
+            _ => None,
         }
+    }
 
-        let header = &body[0];
+    /// Checks the header fields for values real writers produce
+    /// (`--bmp-strict` 1).
+    fn is_plausible(&self) -> bool {
+        // Embedded JPEG and PNG images have no bit count
+        let bpp_valid = match self.compression {
+            BI_JPEG | BI_PNG => self.bpp == 0 || VALID_BPP.contains(&self.bpp),
+            _ => VALID_BPP.contains(&self.bpp),
+        };
+
+        self.planes == 1
+            && bpp_valid
+            && self.width <= MAX_DIMENSION
+            && self.height <= MAX_DIMENSION
+            && (BI_RGB..=BI_ALPHABITFIELDS).contains(&self.compression)
+            && self.colors_used <= 1 << self.bpp.min(16)
+    }
+
+    /// Checks that the compression fits the bit count and that the image
+    /// size in the header fits the dimensions (`--bmp-strict` 2).
+    fn is_consistent(&self) -> bool {
+        let compression_fits = match self.compression {
+            BI_RLE8 => self.bpp == 8,
+            BI_RLE4 => self.bpp == 4,
+            BI_BITFIELDS | BI_ALPHABITFIELDS => matches!(self.bpp, 16 | 32),
+            _ => true,
+        };
+
+        let size_fits = match (self.compression, self.image_size()) {
+            (BI_RGB | BI_BITFIELDS | BI_ALPHABITFIELDS, Some(x)) => {
+                self.size_image == 0 || self.size_image.abs_diff(x) <= MAX_TRAILING_BYTES
+            }
+            _ => true,
+        };
+
+        compression_fits && size_fits
+    }
+}
+
+impl Detector for BitmapDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, opts: &DetectOptions) -> Option<StreamMatch> {
+        let header: BitmapFileHeader = read_struct(buffer.get(offset..)?)?;
 
         if &header.id != b"BM" {
             return None;
         }
 
-        if header.id2 != 40 {
+        if opts.bmp_strict >= 1 && (header.reserved1 != 0 || header.reserved2 != 0) {
             return None;
         }
 
-        if header.size < 14 {
+        let dib = Dib::parse(buffer.get(offset + FILE_HEADER_SIZE..)?)?;
+
+        if dib.width == 0 || dib.height == 0 {
             return None;
         }
 
-        let mut size = usize::try_from(header.size).unwrap();
+        if opts.bmp_strict >= 1 && !dib.is_plausible() {
+            return None;
+        }
 
-        if offset + size > buffer.len() {
-            size = buffer.len() - offset;
+        if opts.bmp_strict >= 2 && !dib.is_consistent() {
+            return None;
         }
 
-        return Some(StreamMatch {
+        // Pixel data starts after the headers, the masks and the palette
+        // unless the header points elsewhere
+        let min_offset = FILE_HEADER_SIZE + dib.header_size + dib.masks_size;
+        let max_offset = min_offset + dib.palette_size;
+        let pixel_offset = match usize::try_from(header.offset).ok()? {
+            x if opts.bmp_strict >= 1 && !(min_offset..=max_offset).contains(&x) => return None,
+            x if x >= min_offset => x,
+            _ => max_offset,
+        };
+
+        let computed_size = pixel_offset
+            .checked_add(dib.image_size()?)?
+            .max(FILE_HEADER_SIZE.saturating_add(dib.profile_end));
+
+        // Writers often leave the file size 0 or wrong, so it is only
+        // trusted when it covers the computed layout
+        let declared_size = usize::try_from(header.size).ok()?;
+        let size_matches =
+            declared_size >= computed_size && declared_size - computed_size <= MAX_TRAILING_BYTES;
+
+        // A file the 32-bit size field cannot describe, or one that claims
+        // a size the pixel data does not lay out, is a false match
+        if opts.bmp_strict >= 1
+            && (computed_size > u32::MAX as usize || declared_size != 0 && !size_matches)
+        {
+            return None;
+        }
+
+        let size = if size_matches {
+            declared_size
+        } else {
+            computed_size
+        };
+
+        let size = size.min(buffer.len() - offset);
+
+        Some(StreamMatch {
             offset,
             size,
             ext: "bmp",
-        });
+            stream_type: StreamType::Bitmap,
+            metadata: Metadata::from([
+                ("width", dib.width.into()),
+                ("height", dib.height.into()),
+                ("bpp", dib.bpp.into()),
+                ("header_size", dib.header_size.into()),
+            ]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::MetaValue;
+
+    /// DIB header of `header_size` bytes with the BITMAPINFOHEADER fields.
+    fn info_header(
+        header_size: usize,
+        width: i32,
+        height: i32,
+        bpp: u16,
+        compression: u32,
+    ) -> Vec<u8> {
+        let mut header = [
+            (header_size as u32).to_le_bytes().as_slice(),
+            &width.to_le_bytes(),
+            &height.to_le_bytes(),
+            &1u16.to_le_bytes(),
+            &bpp.to_le_bytes(),
+            &compression.to_le_bytes(),
+        ]
+        .concat();
+
+        header.resize(header_size, 0);
+        header
+    }
+
+    fn core_header(width: u16, height: u16, bpp: u16) -> Vec<u8> {
+        let fields = [CORE_HEADER_SIZE as u16, 0, width, height, 1, bpp];
+        fields.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// File with the DIB header, `extra` (masks, palette) and `image_size`
+    /// bytes of pixels. The file size is the declared one if set.
+    fn bmp(dib: &[u8], extra: usize, image_size: usize, declared_size: Option<u32>) -> Vec<u8> {
+        let pixel_offset = FILE_HEADER_SIZE + dib.len() + extra;
+        let size = declared_size.unwrap_or((pixel_offset + image_size) as u32);
+
+        [
+            b"BM".as_slice(),
+            &size.to_le_bytes(),
+            &[0; 4],
+            &(pixel_offset as u32).to_le_bytes(),
+            dib,
+            &vec![0; extra],
+            &vec![0x55; image_size],
+        ]
+        .concat()
+    }
+
+    fn detect(file: &[u8], bmp_strict: u8) -> Option<StreamMatch> {
+        let opts = DetectOptions {
+            bmp_strict,
+            ..DetectOptions::default()
+        };
+        let buffer = [file, &[0xAA; 100]].concat();
+
+        BitmapDetector.detect(&buffer, 0, &opts)
+    }
+
+    #[test]
+    fn dib_header_versions() {
+        // 24-bit rows of 30 bytes are padded to 32
+        for header_size in [40, 52, 56, 64, 108, 124] {
+            let file = bmp(&info_header(header_size, 10, 10, 24, BI_RGB), 0, 320, None);
+            let m = detect(&file, 1).unwrap();
+
+            assert_eq!(m.size, file.len(), "{} byte header", header_size);
+            assert_eq!(
+                m.metadata["header_size"],
+                MetaValue::Int(header_size as u64)
+            );
+        }
+
+        // 8-bit with a full palette, top-down
+        let file = bmp(&info_header(40, 100, -50, 8, BI_RGB), 1024, 5000, None);
+        let m = detect(&file, 1).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.metadata["width"], MetaValue::Int(100));
+        assert_eq!(m.metadata["height"], MetaValue::Int(50));
+        assert_eq!(m.metadata["bpp"], MetaValue::Int(8));
+
+        // 16-bit with color masks after BITMAPINFOHEADER
+        let file = bmp(&info_header(40, 10, 10, 16, BI_BITFIELDS), 12, 200, None);
+        assert_eq!(detect(&file, 2).unwrap().size, file.len());
+
+        // OS/2 1.x with an RGBTRIPLE palette
+        let file = bmp(&core_header(16, 16, 4), 48, 128, None);
+        let m = detect(&file, 1).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.metadata["header_size"], MetaValue::Int(12));
+
+        // OS/2 2.x short header
+        let file = bmp(&info_header(16, 16, 16, 1, BI_RGB), 8, 64, None);
+        assert_eq!(detect(&file, 1).unwrap().size, file.len());
+    }
+
+    #[test]
+    fn file_size_is_computed() {
+        // The declared size is 0, or wrong and only accepted at level 0
+        for (declared_size, level) in [(0, 2), (10, 0), (1_000_000, 0)] {
+            let file = bmp(
+                &info_header(40, 10, 10, 24, BI_RGB),
+                0,
+                320,
+                Some(declared_size),
+            );
+            assert_eq!(detect(&file, level).unwrap().size, file.len());
+        }
+
+        // Trailing bytes counted in the declared size
+        let mut file = bmp(&info_header(40, 10, 10, 24, BI_RGB), 0, 320, None);
+        let size = file.len() as u32 + 2;
+        file[2..6].copy_from_slice(&size.to_le_bytes());
+        assert_eq!(detect(&file, 2).unwrap().size, file.len() + 2);
+
+        // A V5 ICC profile after the pixel data
+        let mut dib = info_header(124, 10, 10, 24, BI_RGB);
+        dib[112..116].copy_from_slice(&500u32.to_le_bytes());
+        dib[116..120].copy_from_slice(&100u32.to_le_bytes());
+        let mut file = bmp(&dib, 0, 320, Some(0));
+        file.resize(FILE_HEADER_SIZE + 600, 0x66);
+        assert_eq!(detect(&file, 1).unwrap().size, file.len());
+
+        // Compressed pixel data needs its size in the header
+        let mut dib = info_header(40, 10, 10, 8, BI_RLE8);
+        let file = bmp(&dib, 1024, 70, None);
+        assert!(detect(&file, 1).is_none());
+
+        dib[20..24].copy_from_slice(&70u32.to_le_bytes());
+        let file = bmp(&dib, 1024, 70, None);
+        assert_eq!(detect(&file, 1).unwrap().size, file.len());
+    }
+
+    #[test]
+    fn strict_levels() {
+        // Accepted up to the given level
+        let mut cases = Vec::new();
+
+        // Two planes
+        let mut dib = info_header(40, 10, 10, 24, BI_RGB);
+        dib[12..14].copy_from_slice(&2u16.to_le_bytes());
+        cases.push((bmp(&dib, 0, 320, None), 0));
+
+        // A bit count real writers do not use
+        cases.push((bmp(&info_header(40, 10, 10, 7, BI_RGB), 512, 80, None), 0));
+
+        // A width past the plausible dimensions
+        cases.push((
+            bmp(&info_header(40, 0x10000, 1, 1, BI_RGB), 8, 8192, None),
+            0,
+        ));
+
+        // Pixel data that does not follow the palette
+        let mut file = bmp(&info_header(40, 10, 10, 24, BI_RGB), 0, 320, None);
+        file[10..14].copy_from_slice(&100u32.to_le_bytes());
+        cases.push((file, 0));
+
+        // RLE8 with 4-bit samples
+        let mut dib = info_header(40, 10, 10, 4, BI_RLE8);
+        dib[20..24].copy_from_slice(&70u32.to_le_bytes());
+        cases.push((bmp(&dib, 64, 70, None), 1));
+
+        // An image size that does not fit the dimensions
+        let mut dib = info_header(40, 10, 10, 24, BI_RGB);
+        dib[20..24].copy_from_slice(&1000u32.to_le_bytes());
+        cases.push((bmp(&dib, 0, 320, None), 1));
+
+        // A wrong file size
+        cases.push((
+            bmp(&info_header(40, 10, 10, 24, BI_RGB), 0, 320, Some(10)),
+            0,
+        ));
+
+        // Reserved fields that are not zero
+        let mut file = bmp(&info_header(40, 10, 10, 24, BI_RGB), 0, 320, None);
+        file[6] = 1;
+        cases.push((file, 0));
+
+        // Dimensions whose pixel data does not fit a 32-bit file size
+        cases.push((
+            bmp(&info_header(40, 0xFFFF, 0xFFFF, 32, BI_RGB), 0, 0, Some(0)),
+            0,
+        ));
+
+        for (i, (file, max_level)) in cases.iter().enumerate() {
+            for level in 0..=2 {
+                let m = detect(file, level);
+                assert_eq!(
+                    m.is_some(),
+                    level <= *max_level,
+                    "case {}, level {}",
+                    i,
+                    level
+                );
+            }
+        }
+
+        // A valid file passes all levels
+        let file = bmp(&info_header(40, 10, 10, 24, BI_RGB), 0, 320, None);
+        assert!((0..=2).all(|level| detect(&file, level).is_some()));
     }
 }
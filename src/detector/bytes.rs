@@ -0,0 +1,37 @@
+//! Readers of the integers and structs in binary headers. The integer
+//! readers panic if the value is out of bounds; callers check the length
+//! first.
+
+use std::mem::size_of;
+
+pub fn read_u16_be(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+pub fn read_u32_be(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn read_u32_le(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn read_u64_be(buffer: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buffer[offset..offset + 8].try_into().unwrap())
+}
+
+pub fn read_u64_le(buffer: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads a `#[repr(C, packed)]` struct from the start of `buffer`, or
+/// `None` if the buffer is too short.
+pub fn read_struct<T: Copy>(buffer: &[u8]) -> Option<T> {
+    let (head, body, _tail) = unsafe { buffer.get(..size_of::<T>())?.align_to::<T>() };
+
+    if !head.is_empty() {
+        return None;
+    }
+
+    body.first().copied()
+}
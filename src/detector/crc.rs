@@ -0,0 +1,67 @@
+//! CRCs of the frame headers and frames of FLAC and MPEG audio.
+
+const CRC8_TABLE: [u8; 256] = crc8_table();
+const CRC16_TABLE: [u16; 256] = crc16_table();
+
+/// CRC-8, polynomial x^8 + x^2 + x + 1 (FLAC frame headers)
+const fn crc8_table() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u8;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+/// CRC-16, polynomial x^16 + x^15 + x^2 + 1 (FLAC frames and protected
+/// MPEG audio frames)
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+/// CRC-8 of `bytes`, starting from 0.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0, |crc, &b| CRC8_TABLE[(crc ^ b) as usize])
+}
+
+/// Feeds a byte into a CRC-16 (polynomial 0x8005, unreflected).
+pub fn crc16_update(crc: u16, b: u8) -> u16 {
+    (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ b) as usize]
+}
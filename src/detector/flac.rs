@@ -0,0 +1,550 @@
+use super::bytes::{read_u16_be, read_u64_be};
+use super::crc::{crc16_update, crc8};
+use super::{DetectOptions, Detector, FlacDetector, Metadata, StreamMatch, StreamType};
+
+pub const SIGNATURE: &[u8; 4] = b"fLaC";
+
+const STREAMINFO: u8 = 0;
+const STREAMINFO_LENGTH: usize = 34;
+const INVALID_BLOCK_TYPE: u8 = 127;
+
+#[derive(Debug, Clone, Copy)]
+struct StreamInfo {
+    min_block_size: usize,
+    max_block_size: usize,
+    max_frame_size: usize,
+    sample_rate: u32,
+    channels: u8,
+    bits_per_sample: u8,
+    total_samples: u64,
+}
+
+impl StreamInfo {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let bits = read_u64_be(data, 10);
+
+        let info = StreamInfo {
+            min_block_size: read_u16_be(data, 0) as usize,
+            max_block_size: read_u16_be(data, 2) as usize,
+            max_frame_size: u32::from_be_bytes([0, data[7], data[8], data[9]]) as usize,
+            sample_rate: (bits >> 44) as u32,
+            channels: ((bits >> 41) & 0x07) as u8 + 1,
+            bits_per_sample: ((bits >> 36) & 0x1F) as u8 + 1,
+            total_samples: bits & 0x0F_FFFF_FFFF,
+        };
+
+        let valid = info.min_block_size >= 16
+            && info.max_block_size >= info.min_block_size
+            && info.sample_rate > 0
+            && info.bits_per_sample >= 4;
+
+        valid.then_some(info)
+    }
+
+    /// Upper bound of a frame size (the recorded one or the size of
+    /// uncompressed samples plus headers when it is unknown).
+    fn frame_size_limit(&self) -> usize {
+        if self.max_frame_size > 0 {
+            return self.max_frame_size;
+        }
+
+        let sample_bits = self.channels as usize * (self.bits_per_sample as usize + 1);
+        self.max_block_size * sample_bits / 8 + 64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    len: usize,
+    /// Samples per channel
+    block_size: usize,
+    channel_code: u8,
+    bits_per_sample: u32,
+}
+
+/// Parses the frame header at `offset`. Returns `None` unless the sync
+/// code, the fields and the CRC-8 are valid and agree with STREAMINFO.
+fn parse_frame_header(buffer: &[u8], offset: usize, info: &StreamInfo) -> Option<FrameHeader> {
+    let header = buffer.get(offset..)?;
+    let header = &header[..header.len().min(16)];
+
+    if header.len() < 5 || header[0] != 0xFF || header[1] & 0xFE != 0xF8 {
+        return None;
+    }
+
+    let block_size_code = header[2] >> 4;
+    let sample_rate_code = header[2] & 0x0F;
+    let channel_code = header[3] >> 4;
+    let sample_size_code = (header[3] >> 1) & 0x07;
+
+    if block_size_code == 0 || sample_rate_code == 0x0F || channel_code > 10 {
+        return None;
+    }
+
+    let bits_per_sample = match sample_size_code {
+        0 => info.bits_per_sample as u32,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+        7 => 32,
+        _ => return None,
+    };
+
+    if header[3] & 1 != 0 {
+        return None;
+    }
+
+    let channels = if channel_code < 8 {
+        channel_code + 1
+    } else {
+        2
+    };
+
+    if channels != info.channels {
+        return None;
+    }
+
+    // UTF-8 coded frame/sample number (1 to 7 bytes)
+    let number_len = match header[4].leading_ones() {
+        0 => 1,
+        n @ 2..=7 => n as usize,
+        _ => return None,
+    };
+
+    let mut len = 4 + number_len;
+
+    if header.get(5..len)?.iter().any(|&b| b & 0xC0 != 0x80) {
+        return None;
+    }
+
+    let block_size = match block_size_code {
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => {
+            len += 1;
+            *header.get(len - 1)? as usize + 1
+        }
+        7 => {
+            len += 2;
+            u16::from_be_bytes([*header.get(len - 2)?, *header.get(len - 1)?]) as usize + 1
+        }
+        _ => 256 << (block_size_code - 8),
+    };
+
+    len += match sample_rate_code {
+        12 => 1,
+        13 | 14 => 2,
+        _ => 0,
+    };
+
+    if crc8(header.get(..len)?) != *header.get(len)? {
+        return None;
+    }
+
+    Some(FrameHeader {
+        len: len + 1,
+        block_size,
+        channel_code,
+        bits_per_sample,
+    })
+}
+
+/// Big-endian bit reader over a byte slice.
+struct BitReader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut value = 0;
+
+        for _ in 0..bits {
+            let byte = *self.buffer.get(self.pos / 8)?;
+            value = (value << 1) | u32::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+
+        Some(value)
+    }
+
+    fn skip(&mut self, bits: usize) -> Option<()> {
+        self.pos += bits;
+        (self.pos <= self.buffer.len() * 8).then_some(())
+    }
+
+    /// Reads a unary coded value (number of zero bits before a one).
+    fn skip_unary(&mut self) -> Option<()> {
+        while self.read(1)? == 0 {}
+        Some(())
+    }
+}
+
+/// Skips the Rice coded residual of a subframe.
+fn skip_residual(reader: &mut BitReader, block_size: usize, order: usize) -> Option<()> {
+    let param_bits = match reader.read(2)? {
+        0 => 4,
+        1 => 5,
+        _ => return None,
+    };
+
+    let partition_order = reader.read(4)?;
+    let partition_size = block_size >> partition_order;
+
+    if partition_size << partition_order != block_size || partition_size < order {
+        return None;
+    }
+
+    for partition in 0..1usize << partition_order {
+        let samples = partition_size - if partition == 0 { order } else { 0 };
+        let param = reader.read(param_bits)?;
+
+        if param == (1 << param_bits) - 1 {
+            // Escaped partition with raw samples
+            let bits = reader.read(5)? as usize;
+            reader.skip(bits * samples)?;
+            continue;
+        }
+
+        for _ in 0..samples {
+            reader.skip_unary()?;
+            reader.skip(param as usize)?;
+        }
+    }
+
+    Some(())
+}
+
+/// Walks the subframes of the frame at `offset` bit by bit and returns the
+/// end of the frame if its CRC-16 matches. Used for the last frame, which
+/// has no following frame header to confirm the end.
+fn frame_end(
+    buffer: &[u8],
+    offset: usize,
+    header: &FrameHeader,
+    frame_size_limit: usize,
+) -> Option<usize> {
+    // The frame ends within the size limit, so a unary code running into
+    // the zeros after it is not followed to the end of the buffer
+    let buffer = &buffer[..buffer.len().min(offset + frame_size_limit + 2)];
+
+    let mut reader = BitReader {
+        buffer: &buffer[offset + header.len..],
+        pos: 0,
+    };
+
+    let channels = if header.channel_code < 8 {
+        header.channel_code + 1
+    } else {
+        2
+    };
+
+    for channel in 0..channels {
+        // The side channel has one extra bit
+        let side = match header.channel_code {
+            8 | 10 => channel == 1,
+            9 => channel == 0,
+            _ => false,
+        };
+
+        let mut bits = header.bits_per_sample as usize + usize::from(side);
+
+        if reader.read(1)? != 0 {
+            return None;
+        }
+
+        let subframe_type = reader.read(6)?;
+
+        if reader.read(1)? == 1 {
+            let start = reader.pos;
+            reader.skip_unary()?;
+            bits = bits.checked_sub(reader.pos - start)?;
+        }
+
+        match subframe_type {
+            // Constant
+            0 => reader.skip(bits)?,
+            // Verbatim
+            1 => reader.skip(bits * header.block_size)?,
+            // Fixed predictor
+            8..=12 => {
+                let order = (subframe_type & 0x07) as usize;
+                reader.skip(bits * order)?;
+                skip_residual(&mut reader, header.block_size, order)?;
+            }
+            // LPC
+            32..=63 => {
+                let order = (subframe_type & 0x1F) as usize + 1;
+                reader.skip(bits * order)?;
+
+                let precision = reader.read(4)? as usize + 1;
+
+                if precision == 16 {
+                    return None;
+                }
+
+                reader.skip(5 + precision * order)?;
+                skip_residual(&mut reader, header.block_size, order)?;
+            }
+            _ => return None,
+        }
+    }
+
+    let end = offset + header.len + reader.pos.div_ceil(8) + 2;
+    let frame = buffer.get(offset..end)?;
+    let (data, crc) = frame.split_at(frame.len() - 2);
+
+    let valid = data.iter().fold(0, |crc, &b| crc16_update(crc, b)) == read_u16_be(crc, 0);
+
+    valid.then_some(end)
+}
+
+impl Detector for FlacDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        if !buffer[offset..].starts_with(SIGNATURE) {
+            return None;
+        }
+
+        let mut offset2 = offset + SIGNATURE.len();
+        let mut info = None;
+        let mut blocks: usize = 0;
+
+        // Metadata block chain: STREAMINFO first, then SEEKTABLE,
+        // VORBIS_COMMENT, PICTURE, ... up to the block with the last flag
+        loop {
+            let header = buffer.get(offset2..offset2 + 4)?;
+            let is_last = header[0] & 0x80 != 0;
+            let block_type = header[0] & 0x7F;
+            let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+            if block_type == INVALID_BLOCK_TYPE || (blocks == 0) != (block_type == STREAMINFO) {
+                return None;
+            }
+
+            if block_type == STREAMINFO {
+                if length != STREAMINFO_LENGTH {
+                    return None;
+                }
+
+                info = Some(StreamInfo::parse(
+                    buffer.get(offset2 + 4..offset2 + 4 + length)?,
+                )?);
+            }
+
+            blocks += 1;
+            offset2 += 4 + length;
+
+            if is_last {
+                break;
+            }
+        }
+
+        let info = info?;
+        let frame_size_limit = info.frame_size_limit();
+        let mut frames: usize = 0;
+        let mut samples: u64 = 0;
+
+        // Frames: each one ends where its CRC-16 matches and the next valid
+        // frame header starts. The last frame is walked bit by bit instead.
+        while let Some(header) = parse_frame_header(buffer, offset2, &info) {
+            let limit = buffer.len().min(offset2 + frame_size_limit + 2);
+            let mut is_last =
+                info.total_samples > 0 && samples + header.block_size as u64 >= info.total_samples;
+            let mut end = None;
+
+            if !is_last {
+                let mut crc = buffer[offset2..offset2 + header.len]
+                    .iter()
+                    .fold(0, |crc, &b| crc16_update(crc, b));
+
+                for pos in offset2 + header.len..limit.saturating_sub(1) {
+                    let frame_crc = read_u16_be(buffer, pos);
+
+                    if crc == frame_crc && parse_frame_header(buffer, pos + 2, &info).is_some() {
+                        end = Some(pos + 2);
+                        break;
+                    }
+
+                    crc = crc16_update(crc, buffer[pos]);
+                }
+            }
+
+            if end.is_none() {
+                end = frame_end(buffer, offset2, &header, frame_size_limit);
+                is_last = true;
+            }
+
+            let Some(end) = end else {
+                // The frame is cut by the end of the buffer
+                if limit == buffer.len() {
+                    offset2 = buffer.len();
+                }
+
+                break;
+            };
+
+            frames += 1;
+            samples += header.block_size as u64;
+            offset2 = end;
+
+            if is_last {
+                break;
+            }
+        }
+
+        if frames == 0 {
+            return None;
+        }
+
+        let size = offset2 - offset;
+        let complete = info.total_samples == 0 || samples == info.total_samples;
+
+        Some(StreamMatch {
+            offset,
+            size,
+            ext: "flac",
+            stream_type: StreamType::Flac,
+            metadata: Metadata::from([
+                ("sample_rate", info.sample_rate.into()),
+                ("channels", u32::from(info.channels).into()),
+                ("bits_per_sample", u32::from(info.bits_per_sample).into()),
+                ("total_samples", info.total_samples.into()),
+                ("samples", samples.into()),
+                ("frames", frames.into()),
+                ("complete", complete.into()),
+            ]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::MetaValue;
+
+    const BLOCK_SIZE: usize = 256;
+
+    /// Stereo 16-bit frame of 256 samples with verbatim subframes.
+    fn frame(number: u8) -> Vec<u8> {
+        let mut frame = vec![0xFF, 0xF8, 0x89, 0x18, number];
+        frame.push(crc8(&frame));
+
+        for channel in 0..2u8 {
+            frame.push(0x02);
+            frame
+                .extend((0..BLOCK_SIZE * 2).map(|i| (i as u8).wrapping_mul(31) ^ number ^ channel));
+        }
+
+        let crc = frame.iter().fold(0, |crc, &b| crc16_update(crc, b));
+        frame.extend(crc.to_be_bytes());
+        frame
+    }
+
+    fn flac(total_samples: u64, frames: &[Vec<u8>]) -> Vec<u8> {
+        let bits = (44100u64 << 44) | (1 << 41) | (15 << 36) | total_samples;
+
+        [
+            SIGNATURE.as_slice(),
+            &[0x80, 0, 0, STREAMINFO_LENGTH as u8],
+            &(BLOCK_SIZE as u16).to_be_bytes(),
+            &(BLOCK_SIZE as u16).to_be_bytes(),
+            &[0; 6],
+            &bits.to_be_bytes(),
+            &[0; 16],
+            &frames.concat(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn multi_frame_stream() {
+        let opts = DetectOptions::default();
+        let frames = [frame(0), frame(1), frame(2)];
+        let file = flac(3 * BLOCK_SIZE as u64, &frames);
+        let buffer = [file.as_slice(), &[0xAA; 2000]].concat();
+
+        let m = FlacDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "flac");
+        assert_eq!(m.metadata["sample_rate"], MetaValue::Int(44100));
+        assert_eq!(m.metadata["channels"], MetaValue::Int(2));
+        assert_eq!(m.metadata["bits_per_sample"], MetaValue::Int(16));
+        assert_eq!(m.metadata["frames"], MetaValue::Int(3));
+        assert_eq!(m.metadata["samples"], MetaValue::Int(768));
+        assert_eq!(m.metadata["complete"], MetaValue::Bool(true));
+
+        // Unknown total samples: the last frame is the one not followed
+        // by another frame header
+        let file = flac(0, &frames);
+        let buffer = [file.as_slice(), &[0xAA; 2000]].concat();
+
+        let m = FlacDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.metadata["frames"], MetaValue::Int(3));
+        assert_eq!(m.metadata["complete"], MetaValue::Bool(true));
+    }
+
+    #[test]
+    fn corrupted_frame_stops_the_stream() {
+        let opts = DetectOptions::default();
+        let first_end = flac(0, &[frame(0)]).len();
+
+        // CRC-8 of the second frame header
+        let mut frames = [frame(0), frame(1), frame(2)];
+        frames[1][5] ^= 0x01;
+        let buffer = [flac(768, &frames).as_slice(), &[0xAA; 2000]].concat();
+
+        let m = FlacDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, first_end);
+        assert_eq!(m.metadata["frames"], MetaValue::Int(1));
+        assert_eq!(m.metadata["complete"], MetaValue::Bool(false));
+
+        // CRC-16 of the second frame
+        let mut frames = [frame(0), frame(1), frame(2)];
+        *frames[1].last_mut().unwrap() ^= 0x01;
+        let buffer = [flac(768, &frames).as_slice(), &[0xAA; 2000]].concat();
+
+        let m = FlacDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, first_end);
+        assert_eq!(m.metadata["frames"], MetaValue::Int(1));
+
+        // No valid frame at all
+        let mut frames = [frame(0)];
+        frames[0][5] ^= 0x01;
+        let buffer = [flac(256, &frames).as_slice(), &[0xAA; 2000]].concat();
+        assert!(FlacDetector.detect(&buffer, 0, &opts).is_none());
+    }
+
+    #[test]
+    fn stream_cut_at_the_end() {
+        let opts = DetectOptions::default();
+        let mut buffer = flac(768, &[frame(0), frame(1), frame(2)]);
+        buffer.truncate(buffer.len() - 600);
+
+        let m = FlacDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, buffer.len());
+        assert_eq!(m.metadata["frames"], MetaValue::Int(2));
+        assert_eq!(m.metadata["samples"], MetaValue::Int(512));
+        assert_eq!(m.metadata["complete"], MetaValue::Bool(false));
+    }
+
+    #[test]
+    fn zero_tail_is_not_walked_to_the_end() {
+        let opts = DetectOptions::default();
+
+        // A frame whose first subframe has the wasted bits flag set: the
+        // unary count runs into the zeros that follow
+        let mut header = vec![0xFF, 0xF8, 0x89, 0x18, 0];
+        header.push(crc8(&header));
+        header.push(0x03);
+
+        let file = flac(256, &[header]);
+        let buffer = [file.as_slice(), &vec![0; 64 << 20]].concat();
+
+        let start = std::time::Instant::now();
+        assert!(FlacDetector.detect(&buffer, 0, &opts).is_none());
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,218 @@
+use super::bytes::{read_u32_be, read_u64_be};
+use super::{DetectOptions, Detector, IsoBmffDetector, Metadata, StreamMatch, StreamType};
+
+/// Type of the first box, found 4 bytes after the stream start
+pub const SIGNATURE: &[u8; 4] = b"ftyp";
+pub const SIGNATURE_OFFSET: usize = 4;
+
+/// Box types that may appear at the top level of a file
+const TOP_LEVEL_BOXES: &[&[u8; 4]] = &[
+    b"ftyp", b"moov", b"mdat", b"free", b"skip", b"wide", b"meta", b"moof", b"mfra", b"uuid",
+    b"pdin", b"styp", b"sidx", b"ssix", b"prft", b"emsg", b"pnot", b"junk", b"meco", b"udta",
+];
+
+/// Largest plausible `ftyp` box (major brand, minor version and a list of
+/// compatible brands)
+const MAX_FTYP_SIZE: usize = 1024;
+
+/// File extension for the major brand.
+fn ext_for_brand(brand: &[u8]) -> &'static str {
+    match brand {
+        b"M4A " => "m4a",
+        b"M4B " | b"M4P " => "m4b",
+        b"M4V " | b"M4VH" | b"M4VP" => "m4v",
+        b"qt  " => "mov",
+        b"3g2a" | b"3g2b" | b"3g2c" => "3g2",
+        b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" => "heic",
+        b"mif1" | b"msf1" => "heif",
+        b"avif" | b"avis" => "avif",
+        b"crx " => "cr3",
+        b"f4v " => "f4v",
+        _ if brand.starts_with(b"3gp") || brand.starts_with(b"3ge") => "3gp",
+        _ => "mp4",
+    }
+}
+
+impl Detector for IsoBmffDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        let ftyp_size = read_u32_be(buffer.get(offset..offset + 16)?, 0) as usize;
+
+        if &buffer[offset + 4..offset + 8] != SIGNATURE
+            || !(16..=MAX_FTYP_SIZE).contains(&ftyp_size)
+        {
+            return None;
+        }
+
+        let brand = &buffer[offset + 8..offset + 12];
+
+        if !brand
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || b == &b' ')
+        {
+            return None;
+        }
+
+        let mut offset2 = offset;
+        let mut boxes: usize = 0;
+        let mut has_movie = false;
+
+        // Walk the top-level boxes until one is unknown or does not fit
+        while offset2 + 8 <= buffer.len() {
+            let box_type = &buffer[offset2 + 4..offset2 + 8];
+
+            if !TOP_LEVEL_BOXES.iter().any(|x| *x == box_type) {
+                break;
+            }
+
+            let (header_size, size) = match read_u32_be(buffer, offset2) {
+                // Box extends to the end of the file
+                0 => (8, None),
+                // 64-bit size follows the type
+                1 => {
+                    let Some(bytes) = buffer.get(offset2 + 8..offset2 + 16) else {
+                        break;
+                    };
+
+                    (16, usize::try_from(read_u64_be(bytes, 0)).ok())
+                }
+                size => (8, Some(size as usize)),
+            };
+
+            // Only the media data of a file whose movie box was already
+            // seen may run to the end of the buffer; for anything else the
+            // file ends at the last complete box
+            let end = size.and_then(|x| offset2.checked_add(x));
+            let is_complete = end.is_some_and(|x| x <= buffer.len());
+
+            if !(is_complete || box_type == b"mdat" && has_movie) {
+                break;
+            }
+
+            if size.is_some_and(|x| x < header_size) {
+                break;
+            }
+
+            has_movie |= box_type == b"moov" || box_type == b"moof";
+            boxes += 1;
+
+            match end {
+                Some(end) if is_complete => offset2 = end,
+                _ => {
+                    offset2 = buffer.len();
+                    break;
+                }
+            }
+        }
+
+        // Only `ftyp` is not a file
+        if boxes < 2 {
+            return None;
+        }
+
+        let size = offset2 - offset;
+
+        Some(StreamMatch {
+            offset,
+            size,
+            ext: ext_for_brand(brand),
+            stream_type: StreamType::IsoBmff,
+            metadata: Metadata::from([
+                ("brand", String::from_utf8_lossy(brand).trim_end().into()),
+                ("boxes", boxes.into()),
+            ]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::MetaValue;
+
+    fn make_box(box_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        [
+            &(8 + data.len() as u32).to_be_bytes(),
+            box_type.as_slice(),
+            data,
+        ]
+        .concat()
+    }
+
+    fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+        make_box(
+            b"ftyp",
+            &[brand.as_slice(), &[0; 4], brand, b"isom"].concat(),
+        )
+    }
+
+    fn moov() -> Vec<u8> {
+        make_box(b"moov", &make_box(b"mvhd", &[0; 100]))
+    }
+
+    #[test]
+    fn top_level_boxes_are_walked() {
+        let opts = DetectOptions::default();
+        let file = [ftyp(b"isom"), moov(), make_box(b"mdat", &[0x55; 5000])].concat();
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = IsoBmffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "mp4");
+        assert_eq!(m.metadata["boxes"], MetaValue::Int(3));
+        assert_eq!(m.metadata["brand"], MetaValue::from("isom"));
+
+        // 64-bit box size
+        let mdat = [
+            &1u32.to_be_bytes(),
+            b"mdat".as_slice(),
+            &1016u64.to_be_bytes(),
+            &[0; 1000],
+        ]
+        .concat();
+        let file = [ftyp(b"M4A "), moov(), mdat].concat();
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = IsoBmffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "m4a");
+
+        // Only `ftyp`
+        let buffer = [ftyp(b"isom").as_slice(), &[0xAA; 100]].concat();
+        assert!(IsoBmffDetector.detect(&buffer, 0, &opts).is_none());
+    }
+
+    #[test]
+    fn only_a_final_mdat_runs_to_the_end() {
+        let opts = DetectOptions::default();
+        let to_end = |box_type: &[u8; 4]| [&[0; 4], box_type.as_slice(), &[0x55; 3000]].concat();
+
+        // Size 0 after the movie box
+        let buffer = [ftyp(b"isom"), moov(), to_end(b"mdat")].concat();
+        let m = IsoBmffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, buffer.len());
+
+        // Size 0 without a movie box
+        let buffer = [ftyp(b"isom"), to_end(b"mdat")].concat();
+        assert!(IsoBmffDetector.detect(&buffer, 0, &opts).is_none());
+
+        // Size 0 for another box
+        let file = [ftyp(b"isom"), moov()].concat();
+        let buffer = [file.clone(), to_end(b"free")].concat();
+        let m = IsoBmffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+
+        // A cut media data box
+        let mut mdat = make_box(b"mdat", &[0x55; 5000]);
+        mdat.truncate(2000);
+        let buffer = [ftyp(b"isom"), moov(), mdat].concat();
+        let m = IsoBmffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, buffer.len());
+
+        // A stray box that claims more than the buffer
+        let mut free = make_box(b"free", &[0x55; 5000]);
+        free.truncate(2000);
+        let buffer = [file.clone(), free].concat();
+        let m = IsoBmffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+    }
+}
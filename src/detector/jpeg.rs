@@ -0,0 +1,234 @@
+use super::bytes::read_u16_be;
+use super::{end_or_cut, DetectOptions, Detector, JpegDetector, Metadata, StreamMatch, StreamType};
+
+pub const SIGNATURE: &[u8; 3] = b"\xFF\xD8\xFF";
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const SOS: u8 = 0xDA;
+const TEM: u8 = 0x01;
+
+/// Markers that are not followed by a length field.
+fn is_standalone(marker: u8) -> bool {
+    marker == TEM || (0xD0..=0xD7).contains(&marker)
+}
+
+/// Start-of-frame markers (C4 = DHT, C8 = JPG and CC = DAC are not frames).
+fn is_sof(marker: u8) -> bool {
+    (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC)
+}
+
+/// Returns the position of the marker that ends the entropy-coded data
+/// starting at `offset` (stuffed `FF 00` bytes and RST markers are part of
+/// the data), or `None` if the buffer ends first.
+fn skip_entropy_coded_data(buffer: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        offset += memchr::memchr(0xFF, buffer.get(offset..)?)?;
+
+        match *buffer.get(offset + 1)? {
+            0x00 | 0xD0..=0xD7 => offset += 2,
+            // Fill byte before a marker
+            0xFF => offset += 1,
+            _ => return Some(offset),
+        }
+    }
+}
+
+impl Detector for JpegDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        if !buffer[offset..].starts_with(SIGNATURE) {
+            return None;
+        }
+
+        let mut offset2 = offset + 2;
+        let mut frame = None;
+        let mut has_scan = false;
+        let mut format = "jpeg";
+        let mut end = None;
+
+        // Walk the marker segments. Segments are skipped by their length,
+        // so EXIF thumbnails (with their own SOI/EOI) inside APP1 stay part
+        // of the outer image.
+        while offset2 + 2 <= buffer.len() {
+            if buffer[offset2] != 0xFF {
+                return None;
+            }
+
+            let marker = buffer[offset2 + 1];
+
+            match marker {
+                0xFF => {
+                    offset2 += 1;
+                    continue;
+                }
+                EOI => {
+                    end = Some(offset2 + 2);
+                    break;
+                }
+                0x00 | SOI => return None,
+                _ if is_standalone(marker) => {
+                    offset2 += 2;
+                    continue;
+                }
+                _ => {}
+            }
+
+            if offset2 + 4 > buffer.len() {
+                break;
+            }
+
+            let length = read_u16_be(buffer, offset2 + 2) as usize;
+
+            if length < 2 {
+                return None;
+            }
+
+            let segment = &buffer[offset2 + 4..(offset2 + 2 + length).min(buffer.len())];
+
+            if is_sof(marker) && segment.len() >= 6 {
+                frame = Some((read_u16_be(segment, 3), read_u16_be(segment, 1), segment[5]));
+            }
+
+            match (marker, segment) {
+                (0xE0, [b'J', b'F', b'I', b'F', 0, ..]) => format = "jfif",
+                (0xE1, [b'E', b'x', b'i', b'f', 0, 0, ..]) => format = "exif",
+                _ => {}
+            }
+
+            offset2 += 2 + length;
+
+            if marker == SOS {
+                // A scan needs the frame header first
+                frame?;
+                has_scan = true;
+
+                match skip_entropy_coded_data(buffer, offset2) {
+                    Some(x) => offset2 = x,
+                    None => break,
+                }
+            }
+        }
+
+        let (width, height, components) = frame?;
+
+        if !has_scan || width == 0 {
+            return None;
+        }
+
+        // The segment walk only stops before EOI at the end of the buffer
+        let size = end_or_cut(buffer, end, true)? - offset;
+
+        Some(StreamMatch {
+            offset,
+            size,
+            ext: "jpg",
+            stream_type: StreamType::Jpeg,
+            metadata: Metadata::from([
+                ("width", width.into()),
+                ("height", height.into()),
+                ("components", u32::from(components).into()),
+                ("format", format.into()),
+            ]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::MetaValue;
+
+    fn segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        [
+            &[0xFF, marker],
+            &(data.len() as u16 + 2).to_be_bytes(),
+            data,
+        ]
+        .concat()
+    }
+
+    /// Baseline frame of 320x200 with 3 components.
+    fn sof0() -> Vec<u8> {
+        let mut data = vec![8, 0, 200, 1, 64, 3];
+        data.extend([1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1]);
+        segment(0xC0, &data)
+    }
+
+    /// Scan header and entropy-coded data with stuffed bytes and RST markers.
+    fn scan() -> Vec<u8> {
+        let header = segment(SOS, &[3, 1, 0, 2, 0x11, 3, 0x11, 0, 63, 0]);
+        let data = [
+            0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0xFF, 0xFF, 0xD1, 0x78,
+        ];
+        [header.as_slice(), &data.repeat(50)].concat()
+    }
+
+    fn jpeg(app: Vec<u8>) -> Vec<u8> {
+        [
+            vec![0xFF, SOI],
+            app,
+            segment(0xDB, &[0; 65]),
+            sof0(),
+            segment(0xC4, &[0; 29]),
+            scan(),
+            vec![0xFF, EOI],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn segments_and_scans_are_walked() {
+        let opts = DetectOptions::default();
+        let file = jpeg(segment(0xE0, b"JFIF\0\x01\x02\0\0\x01\0\x01\0\0"));
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = JpegDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "jpg");
+        assert_eq!(m.metadata["width"], MetaValue::Int(320));
+        assert_eq!(m.metadata["height"], MetaValue::Int(200));
+        assert_eq!(m.metadata["components"], MetaValue::Int(3));
+        assert_eq!(m.metadata["format"], MetaValue::from("jfif"));
+
+        // The thumbnail of an EXIF image is part of APP1
+        let thumbnail = jpeg(Vec::new());
+        let exif = [b"Exif\0\0".as_slice(), &thumbnail].concat();
+        let file = jpeg(segment(0xE1, &exif));
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = JpegDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.metadata["format"], MetaValue::from("exif"));
+
+        // A JPEG cut in the scan data ends at the end of the buffer
+        let m = JpegDetector
+            .detect(&file[..file.len() - 100], 0, &opts)
+            .unwrap();
+        assert_eq!(m.size, file.len() - 100);
+    }
+
+    #[test]
+    fn invalid_streams_are_rejected() {
+        let opts = DetectOptions::default();
+        let app0 = segment(0xE0, b"JFIF\0\x01\x02\0\0\x01\0\x01\0\0");
+
+        // No scan
+        let buffer = [&[0xFF, SOI], app0.as_slice(), &sof0(), &[0xFF, EOI]].concat();
+        assert!(JpegDetector.detect(&buffer, 0, &opts).is_none());
+
+        // A scan before the frame header
+        let buffer = [
+            &[0xFF, SOI],
+            app0.as_slice(),
+            &scan(),
+            &sof0(),
+            &[0xFF, EOI],
+        ]
+        .concat();
+        assert!(JpegDetector.detect(&buffer, 0, &opts).is_none());
+
+        // A segment not starting with a marker
+        let buffer = [&[0xFF, SOI], app0.as_slice(), &[0x00; 100]].concat();
+        assert!(JpegDetector.detect(&buffer, 0, &opts).is_none());
+    }
+}
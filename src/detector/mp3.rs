@@ -1,4 +1,7 @@
-use super::{DetectOptions, Detector, Mp3Detector, StreamMatch};
+use super::bytes::{read_u16_be, read_u32_be};
+use super::crc::crc16_update;
+use super::tags::{self, ID3V2_SIGNATURE};
+use super::{DetectOptions, Detector, Metadata, Mp3Detector, StreamMatch, StreamType};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[allow(clippy::enum_variant_names)]
@@ -16,6 +19,14 @@ pub enum MpegLayer {
     Layer3,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ChannelMode {
+    Stereo,
+    JointStereo,
+    DualChannel,
+    Mono,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum BitRate {
     Kbps8,
@@ -32,10 +43,16 @@ pub enum BitRate {
     Kbps128,
     Kbps144,
     Kbps160,
+    Kbps176,
     Kbps192,
     Kbps224,
     Kbps256,
+    Kbps288,
     Kbps320,
+    Kbps352,
+    Kbps384,
+    Kbps416,
+    Kbps448,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -84,26 +101,239 @@ impl BitRate {
             BitRate::Kbps128 => 128_000,
             BitRate::Kbps144 => 144_000,
             BitRate::Kbps160 => 160_000,
+            BitRate::Kbps176 => 176_000,
             BitRate::Kbps192 => 192_000,
             BitRate::Kbps224 => 224_000,
             BitRate::Kbps256 => 256_000,
+            BitRate::Kbps288 => 288_000,
             BitRate::Kbps320 => 320_000,
+            BitRate::Kbps352 => 352_000,
+            BitRate::Kbps384 => 384_000,
+            BitRate::Kbps416 => 416_000,
+            BitRate::Kbps448 => 448_000,
         }
     }
 }
 
+/// Bitrates of MPEG-1 Layer I for the bitrate indexes 1 to 14 (ISO 11172-3)
+const BITRATES_V1_L1: [BitRate; 14] = [
+    BitRate::Kbps32,
+    BitRate::Kbps64,
+    BitRate::Kbps96,
+    BitRate::Kbps128,
+    BitRate::Kbps160,
+    BitRate::Kbps192,
+    BitRate::Kbps224,
+    BitRate::Kbps256,
+    BitRate::Kbps288,
+    BitRate::Kbps320,
+    BitRate::Kbps352,
+    BitRate::Kbps384,
+    BitRate::Kbps416,
+    BitRate::Kbps448,
+];
+
+/// Bitrates of MPEG-1 Layer II
+const BITRATES_V1_L2: [BitRate; 14] = [
+    BitRate::Kbps32,
+    BitRate::Kbps48,
+    BitRate::Kbps56,
+    BitRate::Kbps64,
+    BitRate::Kbps80,
+    BitRate::Kbps96,
+    BitRate::Kbps112,
+    BitRate::Kbps128,
+    BitRate::Kbps160,
+    BitRate::Kbps192,
+    BitRate::Kbps224,
+    BitRate::Kbps256,
+    BitRate::Kbps320,
+    BitRate::Kbps384,
+];
+
+/// Bitrates of MPEG-1 Layer III
+const BITRATES_V1_L3: [BitRate; 14] = [
+    BitRate::Kbps32,
+    BitRate::Kbps40,
+    BitRate::Kbps48,
+    BitRate::Kbps56,
+    BitRate::Kbps64,
+    BitRate::Kbps80,
+    BitRate::Kbps96,
+    BitRate::Kbps112,
+    BitRate::Kbps128,
+    BitRate::Kbps160,
+    BitRate::Kbps192,
+    BitRate::Kbps224,
+    BitRate::Kbps256,
+    BitRate::Kbps320,
+];
+
+/// Bitrates of MPEG-2/2.5 Layer I (ISO 13818-3)
+const BITRATES_V2_L1: [BitRate; 14] = [
+    BitRate::Kbps32,
+    BitRate::Kbps48,
+    BitRate::Kbps56,
+    BitRate::Kbps64,
+    BitRate::Kbps80,
+    BitRate::Kbps96,
+    BitRate::Kbps112,
+    BitRate::Kbps128,
+    BitRate::Kbps144,
+    BitRate::Kbps160,
+    BitRate::Kbps176,
+    BitRate::Kbps192,
+    BitRate::Kbps224,
+    BitRate::Kbps256,
+];
+
+/// Bitrates of MPEG-2/2.5 Layer II and III
+const BITRATES_V2_L2_L3: [BitRate; 14] = [
+    BitRate::Kbps8,
+    BitRate::Kbps16,
+    BitRate::Kbps24,
+    BitRate::Kbps32,
+    BitRate::Kbps40,
+    BitRate::Kbps48,
+    BitRate::Kbps56,
+    BitRate::Kbps64,
+    BitRate::Kbps80,
+    BitRate::Kbps96,
+    BitRate::Kbps112,
+    BitRate::Kbps128,
+    BitRate::Kbps144,
+    BitRate::Kbps160,
+];
+
+/// Highest bitrate of free-format streams
+const MAX_FREE_FORMAT_BPS: u32 = 640_000;
+
+/// Bitrate for a bitrate index (0 = free format and 15 have none).
+fn bitrate(index: u8, version: MpegVersion, layer: MpegLayer) -> Option<BitRate> {
+    let table = match (version, layer) {
+        (MpegVersion::Mpeg1, MpegLayer::Layer1) => &BITRATES_V1_L1,
+        (MpegVersion::Mpeg1, MpegLayer::Layer2) => &BITRATES_V1_L2,
+        (MpegVersion::Mpeg1, MpegLayer::Layer3) => &BITRATES_V1_L3,
+        (_, MpegLayer::Layer1) => &BITRATES_V2_L1,
+        (_, _) => &BITRATES_V2_L2_L3,
+    };
+
+    table.get(usize::from(index).checked_sub(1)?).copied()
+}
+
 #[derive(Debug, Clone)]
 pub struct FrameHeader {
+    pub version: MpegVersion,
     pub layer: MpegLayer,
+    pub sample_rate: SampleRate,
+    pub channel_mode: ChannelMode,
+    pub mode_extension: u8,
+    /// A CRC-16 follows the header
+    pub protected: bool,
+    pub padding: bool,
+    /// The bitrate is not in the header (bitrate index 0); all frames of
+    /// the stream have the same size apart from the padding
+    pub free_format: bool,
+    /// Frame size (0 for free-format frames until the size is known)
     pub data_size: usize,
 }
 
+impl FrameHeader {
+    /// Whether `other` can follow this frame in the same stream. Encoders
+    /// switch between stereo and joint stereo from frame to frame.
+    fn is_compatible(&self, other: &FrameHeader) -> bool {
+        let is_mono = |x: &FrameHeader| x.channel_mode == ChannelMode::Mono;
+
+        self.version == other.version
+            && self.layer == other.layer
+            && self.sample_rate == other.sample_rate
+            && self.free_format == other.free_format
+            && (self.channel_mode == other.channel_mode
+                || !is_mono(self)
+                    && !is_mono(other)
+                    && self.channel_mode != ChannelMode::DualChannel
+                    && other.channel_mode != ChannelMode::DualChannel)
+    }
+
+    /// Size of the padding slot of a padded frame.
+    fn padding_size(&self) -> usize {
+        match (self.padding, self.layer) {
+            (false, _) => 0,
+            (true, MpegLayer::Layer1) => 4,
+            (true, _) => 1,
+        }
+    }
+
+    /// Number of bytes after the CRC that it protects: the bit allocation
+    /// of Layer I and the side info of Layer III. Layer II is not checked,
+    /// as its protected bits depend on the bit allocation tables.
+    fn protected_size(&self) -> Option<usize> {
+        let mono = self.channel_mode == ChannelMode::Mono;
+
+        match (self.layer, self.version) {
+            (MpegLayer::Layer1, _) => {
+                // 4 allocation bits per subband and channel; joint stereo
+                // shares the subbands above the bound
+                let bound = match self.channel_mode {
+                    ChannelMode::Mono => 0,
+                    ChannelMode::JointStereo => 4 * (usize::from(self.mode_extension) + 1),
+                    _ => 32,
+                };
+
+                Some((32 + bound) * 4 / 8)
+            }
+            (MpegLayer::Layer2, _) => None,
+            (MpegLayer::Layer3, MpegVersion::Mpeg1) => Some(if mono { 17 } else { 32 }),
+            (MpegLayer::Layer3, _) => Some(if mono { 9 } else { 17 }),
+        }
+    }
+}
+
+/// Checks the CRC-16 of a protected frame starting at the start of
+/// `frame`. It covers the last 2 header bytes and the protected data.
+/// Frames cut by the end of the buffer before the end of the protected
+/// data pass.
+fn check_crc(frame: &[u8], header: &FrameHeader) -> bool {
+    let Some(size) = header.protected_size() else {
+        return true;
+    };
+
+    let Some(data) = frame.get(6..6 + size) else {
+        return true;
+    };
+
+    let crc = frame[2..4]
+        .iter()
+        .chain(data)
+        .fold(0xFFFF, |crc, &b| crc16_update(crc, b));
+
+    crc == read_u16_be(frame, 4)
+}
+
+/// Frame size for a bitrate in bits per second.
+fn frame_size(version: MpegVersion, layer: MpegLayer, bps: u32, hz: u32, padding: bool) -> usize {
+    let padding = u32::from(padding);
+
+    // Layer I frames are counted in 4-byte slots; MPEG-2/2.5 Layer III
+    // frames hold half as many samples as MPEG-1 ones
+    let size = match (layer, version) {
+        (MpegLayer::Layer1, _) => (12 * bps / hz + padding) * 4,
+        (MpegLayer::Layer2, _) | (MpegLayer::Layer3, MpegVersion::Mpeg1) => {
+            144 * bps / hz + padding
+        }
+        (MpegLayer::Layer3, _) => 72 * bps / hz + padding,
+    };
+
+    size as usize
+}
+
 fn parse_frame_header(bytes: &[u8]) -> Option<FrameHeader> {
-    if bytes[0] != 0xFF && bytes[1] & 0b1110_0000 != 0b1110_0000 {
+    // 11-bit frame sync
+    if bytes[0] != 0xFF || bytes[1] & 0b1110_0000 != 0b1110_0000 {
         return None;
     }
 
-    let crc = bytes[1] & 1 == 0;
+    let protected = bytes[1] & 1 == 0;
 
     let version = match bytes[1] & 0b0001_1000 {
         0b00_000 => MpegVersion::Mpeg2_5,
@@ -119,39 +349,20 @@ fn parse_frame_header(bytes: &[u8]) -> Option<FrameHeader> {
         _ => return None,
     };
 
-    let is_version2 = version == MpegVersion::Mpeg2 || version == MpegVersion::Mpeg2_5;
-
-    let bitrate = match (bytes[2] & 0b1111_0000, is_version2) {
-        (0b0001_0000, false) => BitRate::Kbps32,
-        (0b0010_0000, false) => BitRate::Kbps40,
-        (0b0011_0000, false) => BitRate::Kbps48,
-        (0b0100_0000, false) => BitRate::Kbps56,
-        (0b0101_0000, false) => BitRate::Kbps64,
-        (0b0110_0000, false) => BitRate::Kbps80,
-        (0b0111_0000, false) => BitRate::Kbps96,
-        (0b1000_0000, false) => BitRate::Kbps112,
-        (0b1001_0000, false) => BitRate::Kbps128,
-        (0b1010_0000, false) => BitRate::Kbps160,
-        (0b1011_0000, false) => BitRate::Kbps192,
-        (0b1100_0000, false) => BitRate::Kbps224,
-        (0b1101_0000, false) => BitRate::Kbps256,
-        (0b1110_0000, false) => BitRate::Kbps320,
-
-        (0b0001_0000, true) => BitRate::Kbps8,
-        (0b0010_0000, true) => BitRate::Kbps16,
-        (0b0011_0000, true) => BitRate::Kbps24,
-        (0b0100_0000, true) => BitRate::Kbps32,
-        (0b0101_0000, true) => BitRate::Kbps40,
-        (0b0110_0000, true) => BitRate::Kbps48,
-        (0b0111_0000, true) => BitRate::Kbps56,
-        (0b1000_0000, true) => BitRate::Kbps64,
-        (0b1001_0000, true) => BitRate::Kbps80,
-        (0b1010_0000, true) => BitRate::Kbps96,
-        (0b1011_0000, true) => BitRate::Kbps112,
-        (0b1100_0000, true) => BitRate::Kbps128,
-        (0b1101_0000, true) => BitRate::Kbps144,
-        (0b1110_0000, true) => BitRate::Kbps160,
-        _ => return None,
+    // MPEG 2.5 only defines Layer III
+    if matches!(
+        (version, layer),
+        (MpegVersion::Mpeg2_5, MpegLayer::Layer1 | MpegLayer::Layer2)
+    ) {
+        return None;
+    }
+
+    let bitrate_index = bytes[2] >> 4;
+    let free_format = bitrate_index == 0;
+    let bitrate = if free_format {
+        None
+    } else {
+        Some(bitrate(bitrate_index, version, layer)?)
     };
 
     let padding = bytes[2] & 0b10 != 0;
@@ -169,25 +380,206 @@ fn parse_frame_header(bytes: &[u8]) -> Option<FrameHeader> {
         _ => return None,
     };
 
-    let bits_per_sample = match version {
-        MpegVersion::Mpeg1 => 144,
-        MpegVersion::Mpeg2 => 72,
-        MpegVersion::Mpeg2_5 => 72,
+    let data_size = bitrate.map_or(0, |x| {
+        frame_size(version, layer, x.bps(), sample_rate.hz(), padding)
+    });
+
+    let channel_mode = match bytes[3] >> 6 {
+        0b00 => ChannelMode::Stereo,
+        0b01 => ChannelMode::JointStereo,
+        0b10 => ChannelMode::DualChannel,
+        _ => ChannelMode::Mono,
     };
 
-    let data_size = (bits_per_sample * bitrate.bps() / sample_rate.hz()
-        + if padding { 1 } else { 0 }
-        - if crc { 2 } else { 0 }) as usize;
+    Some(FrameHeader {
+        version,
+        layer,
+        sample_rate,
+        channel_mode,
+        mode_extension: (bytes[3] >> 4) & 0b11,
+        protected,
+        padding,
+        free_format,
+        data_size,
+    })
+}
+
+/// Size of the free-format frame at the start of `bytes`: the distance to
+/// the next free-format header, which has to be followed by another one at
+/// the same distance (give or take the padding) or by the end of the
+/// buffer.
+fn free_format_size(bytes: &[u8], header: &FrameHeader) -> Option<usize> {
+    // Free-format bitrates lie between 8 kbps and MAX_FREE_FORMAT_BPS
+    let hz = header.sample_rate.hz();
+    let min_size = frame_size(header.version, header.layer, 8_000, hz, header.padding);
+    let max_size = frame_size(
+        header.version,
+        header.layer,
+        MAX_FREE_FORMAT_BPS,
+        hz,
+        header.padding,
+    );
 
-    Some(FrameHeader { layer, data_size })
+    let window = bytes.get(..bytes.len().min(max_size + 1))?;
+
+    memchr::memchr_iter(0xFF, window)
+        .filter(|&x| x >= min_size)
+        .find(|&x| {
+            let first = FrameHeader {
+                data_size: x,
+                ..header.clone()
+            };
+
+            read_frame(&bytes[x..], Some(&first)).is_some_and(|next| {
+                let next = x + next.data_size;
+                next >= bytes.len() || read_frame(&bytes[next..], Some(&first)).is_some()
+            })
+        })
+}
+
+/// Xing/Info header fields that are present
+const XING_FRAMES: u32 = 0x1;
+const XING_BYTES: u32 = 0x2;
+const XING_TOC: u32 = 0x4;
+const XING_QUALITY: u32 = 0x8;
+
+/// Offset of the VBRI header in the first frame (header + 32 bytes)
+const VBRI_OFFSET: usize = 36;
+
+/// Encoder version strings that start a LAME tag (FFmpeg writes LAME tags
+/// too)
+const LAME_ENCODERS: &[&[u8; 4]] = &[b"LAME", b"Lavf", b"Lavc"];
+
+/// Encoder version (9) + revision (1) + lowpass (1) + peak (4) + gains (4) +
+/// flags (1) + bitrate (1) + delay and padding (3)
+const LAME_TAG_SIZE: usize = 24;
+
+/// Stream length stored in a Xing/Info or VBRI header in the first frame.
+#[derive(Debug, Clone, Copy)]
+struct VbrHeader {
+    kind: &'static str,
+    /// Number of frames after the frame of the header
+    frames: Option<u32>,
+    /// Size of the stream, including the frame of the header
+    bytes: Option<u32>,
+    /// Encoder delay and padding in samples from a LAME tag
+    delay_padding: Option<(u16, u16)>,
+}
+
+/// Parses the Xing/Info or VBRI header of a Layer III `frame`. Xing/Info
+/// headers follow the side info, VBRI headers are at a fixed offset.
+fn parse_vbr_header(frame: &[u8], header: &FrameHeader) -> Option<VbrHeader> {
+    if header.layer != MpegLayer::Layer3 {
+        return None;
+    }
+
+    let crc_size = if header.protected { 2 } else { 0 };
+    let xing = 4 + crc_size + header.protected_size()?;
+
+    let kind = match frame.get(xing..xing + 4) {
+        Some(b"Xing") => "xing",
+        Some(b"Info") => "info",
+        _ => {
+            let vbri = frame.get(VBRI_OFFSET..VBRI_OFFSET + 18)?;
+
+            if &vbri[..4] != b"VBRI" {
+                return None;
+            }
+
+            return Some(VbrHeader {
+                kind: "vbri",
+                bytes: Some(read_u32_be(vbri, 10)).filter(|&x| x > 0),
+                frames: Some(read_u32_be(vbri, 14)).filter(|&x| x > 0),
+                delay_padding: None,
+            });
+        }
+    };
+
+    let flags = read_u32_be(frame.get(xing..xing + 8)?, 4);
+    let mut offset = xing + 8;
+    let mut field = |flag: u32, size: usize| {
+        let value = frame
+            .get(offset..offset + 4)
+            .filter(|_| flags & flag != 0)
+            .map(|x| read_u32_be(x, 0));
+        offset += if flags & flag != 0 { size } else { 0 };
+        value.filter(|&x| x > 0)
+    };
+
+    let frames = field(XING_FRAMES, 4);
+    let bytes = field(XING_BYTES, 4);
+    field(XING_TOC, 100);
+    field(XING_QUALITY, 4);
+
+    // Delay and padding are two 12-bit numbers at the end of the LAME tag
+    let delay_padding = frame
+        .get(offset..offset + LAME_TAG_SIZE)
+        .filter(|x| LAME_ENCODERS.iter().any(|e| x.starts_with(*e)))
+        .map(|x| {
+            let value = u32::from_be_bytes([0, x[21], x[22], x[23]]);
+            ((value >> 12) as u16, (value & 0xFFF) as u16)
+        });
+
+    Some(VbrHeader {
+        kind,
+        frames,
+        bytes,
+        delay_padding,
+    })
+}
+
+/// Reads the frame at the start of `bytes` if it fits the first frame of
+/// the stream and its CRC is valid.
+fn read_frame(bytes: &[u8], first: Option<&FrameHeader>) -> Option<FrameHeader> {
+    let mut header = parse_frame_header(bytes.get(..4)?)?;
+
+    if first.is_some_and(|x| !x.is_compatible(&header))
+        || header.protected && !check_crc(bytes, &header)
+    {
+        return None;
+    }
+
+    // Free-format frames have the size of the first frame
+    if let Some(first) = first.filter(|_| header.free_format) {
+        header.data_size = first.data_size - first.padding_size() + header.padding_size();
+    }
+
+    Some(header)
+}
+
+/// Finds the next frame after damaged data between `start` and `end`. The
+/// frame has to be followed by another frame, or end at `end` or at the end
+/// of the buffer.
+fn resync(buffer: &[u8], start: usize, end: usize, first: &FrameHeader) -> Option<usize> {
+    let end = end.min(buffer.len());
+
+    memchr::memchr_iter(0xFF, buffer.get(start..end)?)
+        .map(|x| start + x)
+        .find(|&x| {
+            read_frame(&buffer[x..], Some(first)).is_some_and(|header| {
+                let next = x + header.data_size;
+                next >= end || read_frame(&buffer[next..], Some(first)).is_some()
+            })
+        })
 }
 
 impl Detector for Mp3Detector {
     fn detect(&self, buffer: &[u8], offset: usize, opts: &DetectOptions) -> Option<StreamMatch> {
-        let mut offset2 = offset;
         let mut size = 0;
+        let mut tag_names = Vec::new();
+
+        // An ID3v2 tag before the first frame belongs to the stream
+        if buffer[offset..].starts_with(ID3V2_SIGNATURE) {
+            size = tags::id3v2_size(&buffer[offset..])?;
+            tag_names.push("id3v2");
+        }
+
+        let start = offset + size;
+        let mut offset2 = start;
         let mut frames = 0;
-        let mut layer: MpegLayer = MpegLayer::Layer3;
+        let mut first: Option<FrameHeader> = None;
+        let mut vbr: Option<VbrHeader> = None;
+        let mut declared_end = None;
 
         loop {
             if offset + size >= buffer.len() {
@@ -195,44 +587,622 @@ impl Detector for Mp3Detector {
                 break;
             }
 
-            if offset2 + 3 > buffer.len() {
+            if offset2 + 4 > buffer.len() {
+                break;
+            }
+
+            // The stream ends where the VBR header says
+            if declared_end == Some(offset2)
+                || vbr.is_some_and(|x| x.frames.is_some_and(|x| frames == x as usize + 1))
+            {
                 break;
             }
 
             if opts.mpeg_max_frames != 0 && frames >= opts.mpeg_max_frames as usize {
+                // The rest of the declared stream is taken as is
+                if let Some(end) = declared_end.filter(|&x| x > offset2) {
+                    size = end.min(buffer.len()) - offset;
+                }
+
                 break;
             }
 
-            let bytes = &buffer[offset2..offset2 + 3];
+            if let Some(mut frame_header) = read_frame(&buffer[offset2..], first.as_ref()) {
+                if first.is_none() && frame_header.free_format {
+                    frame_header.data_size = free_format_size(&buffer[offset2..], &frame_header)?;
+                }
 
-            if let Some(frame_header) = parse_frame_header(bytes) {
-                if frames > 0 && frame_header.layer != layer {
-                    break;
+                if first.is_none() {
+                    let end = (offset2 + frame_header.data_size).min(buffer.len());
+                    vbr = parse_vbr_header(&buffer[offset2..end], &frame_header);
+                    declared_end = vbr.and_then(|x| x.bytes).map(|x| start + x as usize);
                 }
 
-                layer = frame_header.layer;
                 size += frame_header.data_size;
                 offset2 += frame_header.data_size;
                 frames += 1;
-            } else {
-                break;
+                first.get_or_insert(frame_header);
+                continue;
+            }
+
+            // Damaged frames are skipped up to the declared end
+            match (first.as_ref(), declared_end) {
+                (Some(first), Some(end)) if offset2 < end => {
+                    match resync(buffer, offset2 + 1, end, first) {
+                        Some(next) => {
+                            offset2 = next;
+                            size = next - offset;
+                        }
+                        None => break,
+                    }
+                }
+                _ => break,
             }
         }
 
-        if opts.mpeg_min_frames != 0 && frames <= opts.mpeg_min_frames as usize {
+        // A walk that matches the frame and byte counts of the VBR header
+        // needs no minimum number of frames
+        let walked = offset2 - start;
+        let complete = vbr.is_some_and(|x| {
+            (x.frames.is_some() || x.bytes.is_some())
+                && x.frames.is_none_or(|x| frames == x as usize + 1)
+                && x.bytes.is_none_or(|x| walked == x as usize)
+        });
+
+        if !complete && opts.mpeg_min_frames != 0 && frames <= opts.mpeg_min_frames as usize {
             return None;
         }
 
-        let ext = match layer {
+        let ext = match first?.layer {
             MpegLayer::Layer1 => "mp1",
             MpegLayer::Layer2 => "mp2",
             MpegLayer::Layer3 => "mp3",
         };
 
+        // ID3v1, APEv2 and Lyrics3 tags after the last frame, each at most
+        // once
+        while offset + size < buffer.len() {
+            match tags::trailing_tag(&buffer[offset + size..]) {
+                Some((name, len)) if !tag_names.contains(&name) => {
+                    tag_names.push(name);
+                    size = (size + len).min(buffer.len() - offset);
+                }
+                _ => break,
+            }
+        }
+
+        let mut metadata = Metadata::from([("frames", frames.into())]);
+
+        if let Some(vbr) = vbr {
+            metadata.insert("vbr_header", vbr.kind.into());
+
+            if let Some((delay, padding)) = vbr.delay_padding {
+                metadata.insert("encoder_delay", delay.into());
+                metadata.insert("encoder_padding", padding.into());
+            }
+        }
+
+        if !tag_names.is_empty() {
+            metadata.insert("tags", tag_names.join(",").as_str().into());
+        }
+
         if size > 0 {
-            return Some(StreamMatch { offset, size, ext });
+            return Some(StreamMatch {
+                offset,
+                size,
+                ext,
+                stream_type: StreamType::Mp3,
+                metadata,
+            });
         }
 
-        return None;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::MetaValue;
+
+    /// Bitrates in kbps by version (MPEG-1, MPEG-2/2.5), layer (I, II, III)
+    /// and bitrate index, as printed in ISO 11172-3 and 13818-3
+    const KBPS: [[[u32; 15]; 3]; 2] = [
+        [
+            [
+                0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+            ],
+            [
+                0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+            ],
+            [
+                0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+            ],
+        ],
+        [
+            [
+                0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+            ],
+            [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+            [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        ],
+    ];
+
+    /// Version bits and sample rates by sample rate index
+    const VERSIONS: [(u8, [u32; 3]); 3] = [
+        (0b11, [44_100, 48_000, 32_000]),
+        (0b10, [22_050, 24_000, 16_000]),
+        (0b00, [11_025, 12_000, 8_000]),
+    ];
+
+    /// Layer bits of Layer I, II and III
+    const LAYERS: [u8; 3] = [0b11, 0b10, 0b01];
+
+    /// MPEG 2.5 only defines Layer III.
+    fn is_defined(version_bits: u8, layer_bits: u8) -> bool {
+        version_bits != 0b00 || layer_bits == 0b01
+    }
+
+    /// Header of a frame without CRC.
+    fn header(
+        version: u8,
+        layer: u8,
+        bitrate_index: u8,
+        sample_rate_index: u8,
+        padding: bool,
+    ) -> [u8; 4] {
+        [
+            0xFF,
+            0xE0 | version << 3 | layer << 1 | 1,
+            bitrate_index << 4 | sample_rate_index << 2 | u8::from(padding) << 1,
+            0,
+        ]
+    }
+
+    /// Frame length by the formulas of the standards.
+    fn expected_size(version: usize, layer: usize, kbps: u32, hz: u32, padding: bool) -> usize {
+        let padding = u32::from(padding);
+        let bps = kbps * 1000;
+
+        let size = match (layer, version) {
+            (0, _) => (12 * bps / hz + padding) * 4,
+            (1, _) | (2, 0) => 144 * bps / hz + padding,
+            _ => 72 * bps / hz + padding,
+        };
+
+        size as usize
+    }
+
+    #[test]
+    fn frame_sizes_all_versions_and_layers() {
+        for (v, (version_bits, rates)) in VERSIONS.iter().enumerate() {
+            for (l, layer_bits) in LAYERS.iter().enumerate() {
+                for bitrate_index in 1..15u8 {
+                    for (sample_rate_index, &hz) in rates.iter().enumerate() {
+                        for padding in [false, true] {
+                            let kbps = KBPS[v.min(1)][l][bitrate_index as usize];
+                            let bytes = header(
+                                *version_bits,
+                                *layer_bits,
+                                bitrate_index,
+                                sample_rate_index as u8,
+                                padding,
+                            );
+
+                            if !is_defined(*version_bits, *layer_bits) {
+                                assert!(parse_frame_header(&bytes).is_none());
+                                continue;
+                            }
+
+                            let frame = parse_frame_header(&bytes).unwrap();
+
+                            assert_eq!(
+                                frame.layer,
+                                [MpegLayer::Layer1, MpegLayer::Layer2, MpegLayer::Layer3][l]
+                            );
+                            assert_eq!(
+                                frame.data_size,
+                                expected_size(v, l, kbps, hz, padding),
+                                "version {v}, layer {l}, {kbps} kbps, {hz} Hz, padding {padding}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn known_frame_sizes() {
+        // MPEG-1 Layer III, 128 kbps, 44.1 kHz
+        assert_eq!(
+            parse_frame_header(&header(0b11, 0b01, 9, 0, false))
+                .unwrap()
+                .data_size,
+            417
+        );
+        // MPEG-1 Layer II, 192 kbps, 48 kHz
+        assert_eq!(
+            parse_frame_header(&header(0b11, 0b10, 10, 1, false))
+                .unwrap()
+                .data_size,
+            576
+        );
+        // MPEG-1 Layer I, 384 kbps, 44.1 kHz, padded
+        assert_eq!(
+            parse_frame_header(&header(0b11, 0b11, 12, 0, true))
+                .unwrap()
+                .data_size,
+            420
+        );
+        // MPEG-2 Layer III, 64 kbps, 22.05 kHz
+        assert_eq!(
+            parse_frame_header(&header(0b10, 0b01, 8, 0, false))
+                .unwrap()
+                .data_size,
+            208
+        );
+        // MPEG-2.5 Layer III, 8 kbps, 8 kHz
+        assert_eq!(
+            parse_frame_header(&header(0b00, 0b01, 1, 2, false))
+                .unwrap()
+                .data_size,
+            72
+        );
+    }
+
+    #[test]
+    fn reserved_fields_are_rejected() {
+        // Bitrate index 15
+        assert!(parse_frame_header(&header(0b11, 0b01, 15, 0, false)).is_none());
+        // Sample rate index 3
+        assert!(parse_frame_header(&header(0b11, 0b01, 9, 3, false)).is_none());
+        // Layer bits 00
+        assert!(parse_frame_header(&header(0b11, 0b00, 9, 0, false)).is_none());
+        // Version bits 01
+        assert!(parse_frame_header(&header(0b01, 0b01, 9, 0, false)).is_none());
+        // MPEG 2.5 Layer I and II
+        assert!(parse_frame_header(&header(0b00, 0b11, 9, 0, false)).is_none());
+        assert!(parse_frame_header(&header(0b00, 0b10, 9, 0, false)).is_none());
+    }
+
+    /// Stream of `count` frames with the given header, padded with zeros.
+    fn stream(header: [u8; 4], count: usize) -> Vec<u8> {
+        let size = parse_frame_header(&header).unwrap().data_size;
+        let mut frame = vec![0; size];
+        frame[..4].copy_from_slice(&header);
+        frame.repeat(count)
+    }
+
+    /// CRC-16 of MPEG audio, bit by bit.
+    fn crc16(bytes: &[u8]) -> u16 {
+        let mut crc: u16 = 0xFFFF;
+
+        for &b in bytes {
+            for i in (0..8).rev() {
+                let bit = (b >> i) & 1 == 1;
+                let top = crc & 0x8000 != 0;
+                crc <<= 1;
+
+                if bit != top {
+                    crc ^= 0x8005;
+                }
+            }
+        }
+
+        crc
+    }
+
+    /// Stream of `count` protected frames with a side info of `protected`
+    /// bytes and a valid CRC.
+    fn protected_stream(header: [u8; 4], protected: usize, count: usize) -> Vec<u8> {
+        let size = parse_frame_header(&header).unwrap().data_size;
+        let mut frame: Vec<u8> = (0..size).map(|x| x as u8).collect();
+        frame[..4].copy_from_slice(&header);
+
+        let crc = crc16(&[&frame[2..4], &frame[6..6 + protected]].concat());
+        frame[4..6].copy_from_slice(&crc.to_be_bytes());
+        frame.repeat(count)
+    }
+
+    #[test]
+    fn sync_needs_all_11_bits() {
+        let mut bytes = header(0b11, 0b01, 9, 0, false);
+        bytes[0] = 0xFE;
+        assert!(parse_frame_header(&bytes).is_none());
+
+        let mut bytes = header(0b11, 0b01, 9, 0, false);
+        bytes[1] &= !0b0010_0000;
+        assert!(parse_frame_header(&bytes).is_none());
+    }
+
+    #[test]
+    fn crc_is_checked() {
+        let opts = DetectOptions::default();
+
+        // MPEG-1 Layer III stereo (32 bytes of side info), MPEG-2 Layer
+        // III mono (9 bytes) and MPEG-1 Layer I stereo (32 bytes of bit
+        // allocation)
+        for (header, protected) in [
+            ([0xFF, 0xFA, 0x90, 0x00], 32),
+            ([0xFF, 0xF2, 0x80, 0xC0], 9),
+            ([0xFF, 0xFE, 0x50, 0x00], 32),
+        ] {
+            let mut buffer = protected_stream(header, protected, 30);
+            let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+            assert_eq!(m.size, buffer.len());
+
+            // Damaged side info in the 26th frame ends the stream there
+            let frame_size = buffer.len() / 30;
+            buffer[25 * frame_size + 7] ^= 1;
+            let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+            assert_eq!(m.size, 25 * frame_size);
+        }
+    }
+
+    #[test]
+    fn frames_must_be_consistent() {
+        let opts = DetectOptions::default();
+        let first = stream(header(0b11, 0b01, 9, 0, false), 25);
+
+        // Sample rate changes
+        let buffer = [
+            first.as_slice(),
+            &stream(header(0b11, 0b01, 9, 1, false), 10),
+        ]
+        .concat();
+        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, first.len());
+
+        // Stereo and joint stereo frames mix
+        let mut joint = header(0b11, 0b01, 9, 0, false);
+        joint[3] = 0b0110_0000;
+        let buffer = [first.as_slice(), &stream(joint, 10)].concat();
+        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, buffer.len());
+
+        // Mono frames do not
+        let mut mono = header(0b11, 0b01, 9, 0, false);
+        mono[3] = 0b1100_0000;
+        let buffer = [first.as_slice(), &stream(mono, 10)].concat();
+        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, first.len());
+    }
+
+    #[test]
+    fn streams_are_walked_to_the_end() {
+        let opts = DetectOptions::default();
+
+        for (l, ext) in ["mp1", "mp2", "mp3"].iter().enumerate() {
+            for (version_bits, _) in VERSIONS {
+                if !is_defined(version_bits, LAYERS[l]) {
+                    continue;
+                }
+
+                let buffer = stream(header(version_bits, LAYERS[l], 5, 1, false), 30);
+                let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+
+                assert_eq!(m.size, buffer.len());
+                assert_eq!(m.ext, *ext);
+                assert_eq!(m.metadata["frames"], MetaValue::Int(30));
+            }
+        }
+    }
+
+    #[test]
+    fn tags_are_part_of_the_stream() {
+        let opts = DetectOptions::default();
+        let frames = stream(header(0b11, 0b01, 9, 0, false), 25);
+
+        // ID3v2.3 tag with 100 bytes of frames and padding
+        let mut id3v2 = b"ID3\x03\x00\x00\x00\x00\x00\x64".to_vec();
+        id3v2.resize(110, 0);
+
+        // APEv2 tag with a header, 10 bytes of items and a footer
+        let ape_header = |flags: u32| {
+            [
+                b"APETAGEX".as_slice(),
+                &2000u32.to_le_bytes(),
+                &42u32.to_le_bytes(),
+                &1u32.to_le_bytes(),
+                &flags.to_le_bytes(),
+                &[0; 8],
+            ]
+            .concat()
+        };
+        let ape = [
+            ape_header(1 << 31 | 1 << 29),
+            vec![0; 10],
+            ape_header(1 << 31),
+        ]
+        .concat();
+
+        let lyrics3 = b"LYRICSBEGININD0000210000021LYRICS200".to_vec();
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0);
+
+        let file = [id3v2, frames, ape, lyrics3, id3v1].concat();
+        let buffer = [file.as_slice(), &[0x55; 50]].concat();
+
+        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(
+            m.metadata["tags"],
+            MetaValue::from("id3v2,apev2,lyrics3,id3v1")
+        );
+
+        // A cut ID3v1 tag ends at the end of the buffer
+        let m = Mp3Detector
+            .detect(&file[..file.len() - 20], 0, &opts)
+            .unwrap();
+        assert_eq!(m.size, file.len() - 20);
+
+        // An ID3v2 tag with a size that is not synchsafe
+        let mut buffer = buffer;
+        buffer[9] = 0x80;
+        assert!(Mp3Detector.detect(&buffer, 0, &opts).is_none());
+    }
+
+    /// MPEG-1 Layer III stream of `count` frames whose first frame holds a
+    /// Xing header with the frame and byte counts and a LAME tag.
+    fn xing_stream(count: usize) -> Vec<u8> {
+        let mut buffer = stream(header(0b11, 0b01, 9, 0, false), count);
+        let frames = (count as u32 - 1).to_be_bytes();
+        let bytes = (buffer.len() as u32).to_be_bytes();
+
+        let mut lame = b"LAME3.100".to_vec();
+        lame.resize(21, 0);
+        // 576 samples of delay, 1234 of padding
+        lame.extend([0x24, 0x04, 0xD2]);
+
+        let xing = [b"Xing".as_slice(), &[0, 0, 0, 3], &frames, &bytes, &lame].concat();
+        buffer[36..36 + xing.len()].copy_from_slice(&xing);
+        buffer
+    }
+
+    #[test]
+    fn vbr_headers_give_the_stream_length() {
+        let opts = DetectOptions::default();
+
+        let buffer = xing_stream(30);
+        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, buffer.len());
+        assert_eq!(m.metadata["vbr_header"], MetaValue::from("xing"));
+        assert_eq!(m.metadata["encoder_delay"], MetaValue::Int(576));
+        assert_eq!(m.metadata["encoder_padding"], MetaValue::Int(1234));
+
+        // Short streams need no minimum number of frames when the counts
+        // match
+        let buffer = xing_stream(5);
+        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, buffer.len());
+
+        let mut buffer = stream(header(0b11, 0b01, 9, 0, false), 5);
+        assert!(Mp3Detector.detect(&buffer, 0, &opts).is_none());
+
+        // A VBRI header with counts that do not match
+        buffer[36..54]
+            .copy_from_slice(b"VBRI\x00\x01\x00\x00\x00\x00\x00\x00\x10\x00\x00\x00\x00\x05");
+        assert!(Mp3Detector.detect(&buffer, 0, &opts).is_none());
+
+        // The stream ends at the declared size even if frames follow
+        let buffer = xing_stream(30);
+        let next = stream(header(0b11, 0b01, 9, 0, false), 30);
+        let m = Mp3Detector
+            .detect(&[buffer.as_slice(), &next].concat(), 0, &opts)
+            .unwrap();
+        assert_eq!(m.size, buffer.len());
+    }
+
+    #[test]
+    fn damaged_frames_are_skipped_up_to_the_declared_size() {
+        let opts = DetectOptions::default();
+        let mut buffer = xing_stream(30);
+
+        buffer[10 * 417..10 * 417 + 4].fill(0);
+        buffer[20 * 417 + 100] = 0xFF;
+        buffer[20 * 417 + 1..20 * 417 + 3].fill(0);
+
+        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, buffer.len());
+        assert_eq!(m.metadata["frames"], MetaValue::Int(28));
+
+        // Without a VBR header the stream ends at the first damaged frame
+        let mut buffer = stream(header(0b11, 0b01, 9, 0, false), 30);
+        buffer[25 * 417..25 * 417 + 4].fill(0);
+        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, 25 * 417);
+    }
+
+    /// Stream of `count` frames where every third frame is padded. Free
+    /// format frames are `base_size` bytes long without padding.
+    fn padded_stream(header: [u8; 4], base_size: Option<usize>, count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| {
+                let mut header = header;
+                header[2] |= u8::from(i % 3 == 0) << 1;
+
+                let frame_header = parse_frame_header(&header).unwrap();
+                let size =
+                    base_size.map_or(frame_header.data_size, |x| x + frame_header.padding_size());
+
+                let mut frame = vec![0; size];
+                frame[..4].copy_from_slice(&header);
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn padded_streams_of_all_versions_and_sample_rates() {
+        let opts = DetectOptions::default();
+
+        for (version_bits, rates) in VERSIONS {
+            for layer_bits in LAYERS {
+                if !is_defined(version_bits, layer_bits) {
+                    continue;
+                }
+
+                for sample_rate_index in 0..rates.len() as u8 {
+                    for bitrate_index in 1..15 {
+                        let header = header(
+                            version_bits,
+                            layer_bits,
+                            bitrate_index,
+                            sample_rate_index,
+                            false,
+                        );
+                        let buffer = padded_stream(header, None, 30);
+                        let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+
+                        assert_eq!(m.size, buffer.len(), "{header:02X?}");
+                        assert_eq!(m.metadata["frames"], MetaValue::Int(30));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn free_format_streams_are_walked() {
+        let opts = DetectOptions::default();
+
+        assert!(
+            parse_frame_header(&header(0b11, 0b01, 0, 0, false))
+                .unwrap()
+                .free_format
+        );
+
+        for (version_bits, rates) in VERSIONS {
+            for (l, layer_bits) in LAYERS.iter().enumerate() {
+                if !is_defined(version_bits, *layer_bits) {
+                    continue;
+                }
+
+                for sample_rate_index in 0..rates.len() as u8 {
+                    let header = header(version_bits, *layer_bits, 0, sample_rate_index, false);
+                    let buffer = padded_stream(header, Some(500), 30);
+                    let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+
+                    assert_eq!(m.size, buffer.len(), "{header:02X?}");
+                    assert_eq!(m.ext, ["mp1", "mp2", "mp3"][l]);
+                    assert_eq!(m.metadata["frames"], MetaValue::Int(30));
+
+                    // Frames with an indexed bitrate are another stream
+                    let buffer = [
+                        buffer.as_slice(),
+                        &stream([header[0], header[1], header[2] | 0x50, header[3]], 10),
+                    ]
+                    .concat();
+                    let m = Mp3Detector.detect(&buffer, 0, &opts).unwrap();
+                    assert_eq!(m.metadata["frames"], MetaValue::Int(30));
+                }
+            }
+        }
+
+        // No second frame within the largest free-format frame size
+        let mut buffer = padded_stream(header(0b11, 0b01, 0, 0, false), Some(500), 30);
+        buffer.splice(500..500, vec![0; 3000]);
+        assert!(Mp3Detector.detect(&buffer, 0, &opts).is_none());
     }
 }
@@ -1,7 +1,8 @@
-use super::{DetectOptions, Detector, OggDetector, StreamMatch};
+use super::bytes::read_struct;
+use super::{DetectOptions, Detector, Metadata, OggDetector, StreamMatch, StreamType};
 
 #[repr(C, packed)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy)]
 struct OggHeader {
     id: [u8; 4],
     revision: u8,
@@ -18,24 +19,11 @@ impl Detector for OggDetector {
         let mut size = 0;
         let mut offset2 = offset;
         let mut first_occurrence = true;
+        let mut pages: usize = 0;
 
-        loop {
-            if offset2 + std::mem::size_of::<OggHeader>() > buffer.len() {
-                break;
-            }
-
-            let (head, body, _tail) = unsafe {
-                &buffer[offset2..offset2 + std::mem::size_of::<OggHeader>()].align_to::<OggHeader>()
-            };
-
+        while let Some(header) = buffer.get(offset2..).and_then(read_struct::<OggHeader>) {
             offset2 += std::mem::size_of::<OggHeader>();
 
-            if !head.is_empty() {
-                break;
-            }
-
-            let header = &body[0];
-
             if &header.id != b"OggS" && header.revision != 0 {
                 break;
             }
@@ -55,12 +43,13 @@ impl Detector for OggDetector {
             }
 
             size += std::mem::size_of::<OggHeader>() + header.num_page_segments as usize;
+            pages += 1;
 
             if (header.bit_flags & 4) == 4 {
                 break;
             }
 
-            offset2 = offset + size as usize;
+            offset2 = offset + size;
             first_occurrence = false;
         }
 
@@ -72,10 +61,12 @@ impl Detector for OggDetector {
             size = buffer.len() - offset;
         }
 
-        return Some(StreamMatch {
+        Some(StreamMatch {
             offset,
             size,
             ext: "ogg",
-        });
+            stream_type: StreamType::Ogg,
+            metadata: Metadata::from([("pages", pages.into())]),
+        })
     }
 }
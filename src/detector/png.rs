@@ -0,0 +1,158 @@
+use super::bytes::read_u32_be;
+use super::{end_or_cut, DetectOptions, Detector, Metadata, PngDetector, StreamMatch, StreamType};
+
+pub const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Chunks longer than this are invalid (PNG spec, 2^31 - 1)
+const MAX_CHUNK_LENGTH: usize = 0x7FFF_FFFF;
+
+/// Length (4) + type (4) before the data, CRC (4) after it
+const CHUNK_OVERHEAD: usize = 12;
+
+impl Detector for PngDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        if !buffer[offset..].starts_with(SIGNATURE) {
+            return None;
+        }
+
+        let mut offset2 = offset + SIGNATURE.len();
+        let mut chunks: usize = 0;
+        let mut header = None;
+        let mut iend = None;
+
+        // Walk the chunk chain up to IEND, checking the CRC of every chunk
+        while offset2 + CHUNK_OVERHEAD <= buffer.len() {
+            let length = read_u32_be(buffer, offset2) as usize;
+            let id = &buffer[offset2 + 4..offset2 + 8];
+
+            if length > MAX_CHUNK_LENGTH || !id.iter().all(u8::is_ascii_alphabetic) {
+                return None;
+            }
+
+            let end = offset2 + CHUNK_OVERHEAD + length;
+
+            if end > buffer.len() {
+                break;
+            }
+
+            if chunks == 0 && (id != b"IHDR" || length != 13) {
+                return None;
+            }
+
+            let crc = read_u32_be(buffer, end - 4);
+
+            if crc32fast::hash(&buffer[offset2 + 4..end - 4]) != crc {
+                return None;
+            }
+
+            if chunks == 0 {
+                let data = &buffer[offset2 + 8..offset2 + 8 + length];
+                header = Some((read_u32_be(data, 0), read_u32_be(data, 4), data[8], data[9]));
+            }
+
+            chunks += 1;
+            offset2 = end;
+
+            if id == b"IEND" {
+                iend = Some(offset2);
+                break;
+            }
+        }
+
+        let (width, height, bit_depth, color_type) = header?;
+
+        // The chunk walk only stops before IEND at the end of the buffer
+        let size = end_or_cut(buffer, iend, true)? - offset;
+
+        Some(StreamMatch {
+            offset,
+            size,
+            ext: "png",
+            stream_type: StreamType::Png,
+            metadata: Metadata::from([
+                ("width", width.into()),
+                ("height", height.into()),
+                ("bit_depth", u32::from(bit_depth).into()),
+                ("color_type", u32::from(color_type).into()),
+                ("chunks", chunks.into()),
+            ]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::MetaValue;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let crc = crc32fast::hash(&[id.as_slice(), data].concat());
+        [
+            &(data.len() as u32).to_be_bytes(),
+            id,
+            data,
+            &crc.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn ihdr() -> Vec<u8> {
+        let data = [
+            &640u32.to_be_bytes(),
+            &480u32.to_be_bytes(),
+            [8, 6, 0, 0, 0].as_slice(),
+        ];
+        chunk(b"IHDR", &data.concat())
+    }
+
+    #[test]
+    fn chunks_are_walked_to_iend() {
+        let opts = DetectOptions::default();
+        let file = [
+            SIGNATURE.to_vec(),
+            ihdr(),
+            chunk(b"tEXt", b"Comment\0test"),
+            chunk(b"IDAT", &[0x55; 1000]),
+            chunk(b"IEND", &[]),
+        ]
+        .concat();
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = PngDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "png");
+        assert_eq!(m.metadata["width"], MetaValue::Int(640));
+        assert_eq!(m.metadata["height"], MetaValue::Int(480));
+        assert_eq!(m.metadata["bit_depth"], MetaValue::Int(8));
+        assert_eq!(m.metadata["color_type"], MetaValue::Int(6));
+        assert_eq!(m.metadata["chunks"], MetaValue::Int(4));
+
+        // A PNG cut in IDAT ends at the end of the buffer
+        let m = PngDetector
+            .detect(&file[..file.len() - 500], 0, &opts)
+            .unwrap();
+        assert_eq!(m.size, file.len() - 500);
+        assert_eq!(m.metadata["chunks"], MetaValue::Int(2));
+    }
+
+    #[test]
+    fn invalid_chunks_are_rejected() {
+        let opts = DetectOptions::default();
+        let iend = chunk(b"IEND", &[]);
+
+        // A bad CRC
+        let mut idat = chunk(b"IDAT", &[0x55; 100]);
+        idat[50] ^= 0x01;
+        let buffer = [SIGNATURE.to_vec(), ihdr(), idat, iend.clone(), vec![0; 100]].concat();
+        assert!(PngDetector.detect(&buffer, 0, &opts).is_none());
+
+        // IHDR is not the first chunk
+        let idat = chunk(b"IDAT", &[0x55; 100]);
+        let buffer = [SIGNATURE.to_vec(), idat, ihdr(), iend.clone(), vec![0; 100]].concat();
+        assert!(PngDetector.detect(&buffer, 0, &opts).is_none());
+
+        // No IEND before other data
+        let buffer = [SIGNATURE.to_vec(), ihdr(), vec![0xAA; 100]].concat();
+        assert!(PngDetector.detect(&buffer, 0, &opts).is_none());
+    }
+}
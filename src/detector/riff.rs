@@ -0,0 +1,439 @@
+use super::bytes::{read_u32_be, read_u32_le, read_u64_le};
+use super::{DetectOptions, Detector, Metadata, RiffDetector, StreamMatch, StreamType};
+
+pub const SIGNATURE: &[u8; 4] = b"RIFF";
+/// Big-endian RIFF
+pub const RIFX_SIGNATURE: &[u8; 4] = b"RIFX";
+/// EA IFF 85 (big-endian, used by AIFF)
+pub const IFF_SIGNATURE: &[u8; 4] = b"FORM";
+pub const RF64_SIGNATURES: &[&[u8]] = &[b"RF64", b"BW64"];
+/// GUID of the Wave64 `riff` header
+pub const WAVE64_SIGNATURE: &[u8; 16] = b"riff\x2E\x91\xCF\x11\xA5\xD6\x28\xDB\x04\xC1\x00\x00";
+
+/// GUID of the Wave64 `wave` form type
+const WAVE64_WAVE: &[u8; 16] = b"wave\xF3\xAC\xD3\x11\x8C\xD1\x00\xC0\x4F\x8E\xDB\x8A";
+
+/// GUID (16) + size (8) + form type GUID (16)
+const WAVE64_HEADER_SIZE: usize = 40;
+
+/// GUID (16) + size (8)
+const WAVE64_CHUNK_HEADER_SIZE: usize = 24;
+
+/// RIFF size (8) + data size (8) + sample count (8) + table length (4)
+const DS64_SIZE: usize = 28;
+
+/// Id (4) + size (4)
+const CHUNK_HEADER_SIZE: usize = 8;
+
+/// Deepest `LIST` nesting that is walked
+const MAX_LIST_DEPTH: usize = 8;
+
+/// Known form types and the extension of their files
+const FORMS: &[(&[u8; 4], &str)] = &[
+    (b"AVI ", "avi"),
+    (b"WEBP", "webp"),
+    (b"RMID", "rmi"),
+    (b"ACON", "ani"),
+    (b"DLS ", "dls"),
+    (b"CDXA", "dat"),
+    (b"PAL ", "pal"),
+];
+
+fn is_fourcc(id: &[u8]) -> bool {
+    id.iter().all(|x| (0x20..=0x7E).contains(x))
+}
+
+/// Top-level chunk of a RIFF file.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk {
+    pub id: [u8; 4],
+    /// Offset of the chunk data
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// Checked chunk tree of a RIFF file.
+#[derive(Debug, Clone)]
+pub struct Riff {
+    pub form: [u8; 4],
+    /// End of the file as declared by the header (may lie past the buffer)
+    pub end: usize,
+    pub chunks: Vec<Chunk>,
+    /// Number of chunks nested in `LIST` chunks
+    pub nested_chunks: usize,
+}
+
+impl Riff {
+    /// Parses the RIFF header at `offset` and walks the chunk tree. Chunks
+    /// must fit in their parent; a tree cut by the end of the buffer is
+    /// walked up to the cut.
+    pub fn parse(buffer: &[u8], offset: usize) -> Option<Riff> {
+        Riff::parse_with(buffer, offset, SIGNATURE, read_u32_le)
+    }
+
+    /// Parses a big-endian RIFX file at `offset`.
+    pub fn parse_rifx(buffer: &[u8], offset: usize) -> Option<Riff> {
+        Riff::parse_with(buffer, offset, RIFX_SIGNATURE, read_u32_be)
+    }
+
+    /// Parses an IFF `FORM` at `offset`. IFF chunks are laid out like RIFF
+    /// chunks with big-endian sizes.
+    pub fn parse_iff(buffer: &[u8], offset: usize) -> Option<Riff> {
+        Riff::parse_with(buffer, offset, IFF_SIGNATURE, read_u32_be)
+    }
+
+    fn parse_with(
+        buffer: &[u8],
+        offset: usize,
+        signature: &[u8; 4],
+        read_u32: fn(&[u8], usize) -> u32,
+    ) -> Option<Riff> {
+        let header = buffer.get(offset..offset + 12)?;
+
+        if &header[..4] != signature {
+            return None;
+        }
+
+        let size = read_u32(header, 4) as usize;
+
+        if size < 4 {
+            return None;
+        }
+
+        Riff::walk(buffer, offset, size, &[], read_u32)
+    }
+
+    /// Parses an RF64/BW64 file at `offset`. The 32-bit sizes of the header
+    /// and of large chunks are -1, the real sizes are in the `ds64` chunk
+    /// that comes first.
+    pub fn parse_rf64(buffer: &[u8], offset: usize) -> Option<Riff> {
+        let header = buffer.get(offset..offset + 12)?;
+
+        if !RF64_SIGNATURES.contains(&&header[..4]) {
+            return None;
+        }
+
+        let ds64 = buffer.get(offset + 12..offset + 12 + CHUNK_HEADER_SIZE)?;
+        let ds64_size = read_u32_le(ds64, 4) as usize;
+
+        if &ds64[..4] != b"ds64" || ds64_size < DS64_SIZE {
+            return None;
+        }
+
+        let data = offset + 12 + CHUNK_HEADER_SIZE;
+        let ds64 = buffer.get(data..data + ds64_size)?;
+        let size = usize::try_from(read_u64_le(ds64, 0)).ok()?;
+
+        let mut sizes = vec![(*b"data", usize::try_from(read_u64_le(ds64, 8)).ok()?)];
+        let table_length = read_u32_le(ds64, 24) as usize;
+
+        for entry in ds64[DS64_SIZE..].chunks_exact(12).take(table_length) {
+            let size = usize::try_from(read_u64_le(entry, 4)).ok()?;
+            sizes.push((entry[..4].try_into().unwrap(), size));
+        }
+
+        Riff::walk(buffer, offset, size, &sizes, read_u32_le)
+    }
+
+    /// Parses a Sony Wave64 file at `offset`. Chunk ids are GUIDs whose
+    /// first 4 bytes are the RIFF id, and sizes are 64-bit and include the
+    /// chunk header.
+    pub fn parse_wave64(buffer: &[u8], offset: usize) -> Option<Riff> {
+        let header = buffer.get(offset..offset + WAVE64_HEADER_SIZE)?;
+
+        if &header[..16] != WAVE64_SIGNATURE || &header[24..] != WAVE64_WAVE {
+            return None;
+        }
+
+        let size = usize::try_from(read_u64_le(header, 16)).ok()?;
+
+        if size < WAVE64_HEADER_SIZE {
+            return None;
+        }
+
+        let mut riff = Riff {
+            form: *b"WAVE",
+            end: offset.checked_add(size)?,
+            chunks: Vec::new(),
+            nested_chunks: 0,
+        };
+
+        let mut offset2 = offset + WAVE64_HEADER_SIZE;
+
+        while offset2 + WAVE64_CHUNK_HEADER_SIZE <= riff.end.min(buffer.len()) {
+            let size = usize::try_from(read_u64_le(buffer, offset2 + 16)).ok()?;
+
+            if size < WAVE64_CHUNK_HEADER_SIZE || size > riff.end - offset2 {
+                return None;
+            }
+
+            riff.chunks.push(Chunk {
+                id: buffer[offset2..offset2 + 4].try_into().unwrap(),
+                offset: offset2 + WAVE64_CHUNK_HEADER_SIZE,
+                size: size - WAVE64_CHUNK_HEADER_SIZE,
+            });
+
+            // Chunks are aligned to 8 bytes
+            offset2 = offset2
+                .saturating_add(size.next_multiple_of(8))
+                .min(riff.end);
+        }
+
+        Some(riff)
+    }
+
+    /// Walks the chunk tree of a file with the `size` from its header.
+    /// `sizes` replaces the 32-bit size of top-level chunks whose size is -1.
+    fn walk(
+        buffer: &[u8],
+        offset: usize,
+        size: usize,
+        sizes: &[([u8; 4], usize)],
+        read_u32: fn(&[u8], usize) -> u32,
+    ) -> Option<Riff> {
+        let form = &buffer[offset + 8..offset + 12];
+
+        if !is_fourcc(form) {
+            return None;
+        }
+
+        let mut riff = Riff {
+            form: form.try_into().unwrap(),
+            end: size.checked_add(offset + CHUNK_HEADER_SIZE)?,
+            chunks: Vec::new(),
+            nested_chunks: 0,
+        };
+
+        let end = riff.end;
+
+        walk(
+            buffer,
+            offset + 12,
+            end,
+            0,
+            sizes,
+            read_u32,
+            &mut |chunk, depth| {
+                if depth == 0 {
+                    riff.chunks.push(chunk);
+                } else {
+                    riff.nested_chunks += 1;
+                }
+            },
+        )?;
+
+        Some(riff)
+    }
+}
+
+/// Walks the chunks between `start` and `end`, descending into `LIST`
+/// chunks. Returns `None` if a chunk is malformed or overruns `end`.
+fn walk(
+    buffer: &[u8],
+    start: usize,
+    end: usize,
+    depth: usize,
+    sizes: &[([u8; 4], usize)],
+    read_u32: fn(&[u8], usize) -> u32,
+    f: &mut impl FnMut(Chunk, usize),
+) -> Option<()> {
+    let mut offset = start;
+
+    while offset + CHUNK_HEADER_SIZE <= end.min(buffer.len()) {
+        let id = &buffer[offset..offset + 4];
+        let mut size = read_u32(buffer, offset + 4) as usize;
+        let data = offset + CHUNK_HEADER_SIZE;
+
+        if size == u32::MAX as usize {
+            if let Some((_, x)) = sizes.iter().find(|(x, _)| x == id) {
+                size = *x;
+            }
+        }
+
+        if !is_fourcc(id) || size > end - data {
+            return None;
+        }
+
+        if id == b"LIST" {
+            if size < 4 || depth >= MAX_LIST_DEPTH {
+                return None;
+            }
+
+            if data + 4 <= buffer.len() {
+                if !is_fourcc(&buffer[data..data + 4]) {
+                    return None;
+                }
+
+                walk(buffer, data + 4, data + size, depth + 1, &[], read_u32, f)?;
+            }
+        }
+
+        f(
+            Chunk {
+                id: id.try_into().unwrap(),
+                offset: data,
+                size,
+            },
+            depth,
+        );
+
+        // Chunks are padded to an even size; writers that drop the pad
+        // byte of the last chunk are tolerated
+        offset = (data + size + (size & 1)).min(end);
+    }
+
+    Some(())
+}
+
+impl Detector for RiffDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        let riff = Riff::parse(buffer, offset)?;
+
+        // WAVE files are validated by the WAVE detector
+        if &riff.form == b"WAVE" || riff.chunks.is_empty() {
+            return None;
+        }
+
+        let ext = FORMS
+            .iter()
+            .find(|(form, _)| **form == riff.form)
+            .map_or("riff", |(_, ext)| ext);
+
+        let mut end = riff.end;
+
+        // OpenDML AVI files continue in `AVIX` RIFF files right after the
+        // first one
+        if &riff.form == b"AVI " {
+            while let Some(next) = Riff::parse(buffer, end).filter(|x| &x.form == b"AVIX") {
+                end = next.end;
+            }
+        }
+
+        let size = end.min(buffer.len()) - offset;
+
+        Some(StreamMatch {
+            offset,
+            size,
+            ext,
+            stream_type: StreamType::Riff,
+            metadata: Metadata::from([
+                (
+                    "form",
+                    String::from_utf8_lossy(&riff.form).trim_end().into(),
+                ),
+                ("chunks", riff.chunks.len().into()),
+                ("nested_chunks", riff.nested_chunks.into()),
+            ]),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::detector::MetaValue;
+
+    fn chunk_with_size(id: &[u8; 4], size: [u8; 4], data: &[u8]) -> Vec<u8> {
+        let pad = vec![0; data.len() & 1];
+        [id.as_slice(), &size, data, &pad].concat()
+    }
+
+    /// Chunk with the pad byte of odd sizes.
+    pub(crate) fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        chunk_with_size(id, (data.len() as u32).to_le_bytes(), data)
+    }
+
+    /// Big-endian chunk (RIFX, AIFF) with the pad byte of odd sizes.
+    pub(crate) fn chunk_be(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        chunk_with_size(id, (data.len() as u32).to_be_bytes(), data)
+    }
+
+    fn list(list_type: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(b"LIST", &[list_type.as_slice(), &chunks.concat()].concat())
+    }
+
+    pub(crate) fn riff(form: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let data = [form.as_slice(), &chunks.concat()].concat();
+        chunk_with_size(b"RIFF", (data.len() as u32).to_le_bytes(), &data)
+    }
+
+    /// Big-endian file (`RIFX`, `FORM`) of the given form type.
+    pub(crate) fn riff_be(signature: &[u8; 4], form: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let data = [form.as_slice(), &chunks.concat()].concat();
+        chunk_with_size(signature, (data.len() as u32).to_be_bytes(), &data)
+    }
+
+    #[test]
+    fn forms_are_detected() {
+        let opts = DetectOptions::default();
+        let file = riff(b"WEBP", &[chunk(b"VP8 ", &[0x55; 101])]);
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = RiffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "webp");
+        assert_eq!(m.metadata["form"], MetaValue::from("WEBP"));
+        assert_eq!(m.metadata["chunks"], MetaValue::Int(1));
+
+        // Unknown form types get a generic extension
+        let file = riff(b"ABCD", &[chunk(b"data", &[0x55; 10])]);
+        let m = RiffDetector.detect(&file, 0, &opts).unwrap();
+        assert_eq!(m.ext, "riff");
+        assert_eq!(m.metadata["form"], MetaValue::from("ABCD"));
+
+        // WAVE is left to the WAVE detector
+        let file = riff(b"WAVE", &[chunk(b"data", &[0x55; 10])]);
+        assert!(RiffDetector.detect(&file, 0, &opts).is_none());
+
+        // A RIFF cut by the end of the buffer
+        let file = riff(b"WEBP", &[chunk(b"VP8 ", &[0x55; 1000])]);
+        let m = RiffDetector.detect(&file[..500], 0, &opts).unwrap();
+        assert_eq!(m.size, 500);
+    }
+
+    #[test]
+    fn avi_lists_and_avix_parts() {
+        let opts = DetectOptions::default();
+        let hdrl = list(
+            b"hdrl",
+            &[
+                chunk(b"avih", &[0; 56]),
+                list(
+                    b"strl",
+                    &[chunk(b"strh", &[0; 56]), chunk(b"strf", &[0; 40])],
+                ),
+            ],
+        );
+        let movi = list(b"movi", &[chunk(b"00dc", &[0x55; 1001])]);
+        let avi = riff(b"AVI ", &[hdrl, movi.clone()]);
+        let avix = riff(b"AVIX", &[movi]);
+        let file = [avi.as_slice(), &avix, &avix].concat();
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = RiffDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "avi");
+        assert_eq!(m.metadata["chunks"], MetaValue::Int(2));
+        assert_eq!(m.metadata["nested_chunks"], MetaValue::Int(5));
+    }
+
+    #[test]
+    fn malformed_chunks_are_rejected() {
+        let opts = DetectOptions::default();
+
+        // A chunk larger than the file
+        let mut file = riff(b"WEBP", &[chunk(b"VP8 ", &[0x55; 100])]);
+        file[16..20].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(RiffDetector
+            .detect(&[file, vec![0; 2000]].concat(), 0, &opts)
+            .is_none());
+
+        // A chunk id that is not a FourCC
+        let file = riff(b"WEBP", &[chunk(b"VP8\0", &[0x55; 100])]);
+        assert!(RiffDetector.detect(&file, 0, &opts).is_none());
+
+        // A form type that is not a FourCC
+        let file = riff(b"\0\0\0\0", &[chunk(b"data", &[0x55; 100])]);
+        assert!(RiffDetector.detect(&file, 0, &opts).is_none());
+    }
+}
@@ -1,20 +1,21 @@
-use super::{DetectOptions, Detector, RiffWaveDetector, StreamMatch};
-use std::mem::size_of;
+use super::bytes::read_struct;
+use super::riff::Riff;
+use super::{
+    DetectOptions, Detector, Metadata, Rf64Detector, RiffWaveDetector, RifxDetector, StreamMatch,
+    StreamType, Wave64Detector,
+};
 
-#[repr(C, packed)]
-#[derive(Debug, Clone, Copy, Default)]
-struct RiffHeader {
-    id: [u8; 4],
-    chunk_size: u32,
-    format: [u8; 4],
-}
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_ADPCM: u16 = 0x0002;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_ALAW: u16 = 0x0006;
+const WAVE_FORMAT_MULAW: u16 = 0x0007;
+const WAVE_FORMAT_IMA_ADPCM: u16 = 0x0011;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
 
-#[repr(C, packed)]
-#[derive(Debug, Clone, Copy, Default)]
-struct WaveChunk {
-    id: [u8; 4],
-    size: u32,
-}
+/// Sub-format GUIDs of WAVE_FORMAT_EXTENSIBLE are the format tag followed
+/// by this suffix
+const KSDATAFORMAT_SUFFIX: &[u8; 14] = b"\x00\x00\x00\x00\x10\x00\x80\x00\x00\xAA\x00\x38\x9B\x71";
 
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
@@ -35,85 +36,480 @@ struct WaveFormatPCM {
 
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
-struct RiffWavePCMHeader {
-    header: RiffHeader,
-    chunk1: WaveChunk,
+struct WaveFormatExtensible {
     pcm_format: WaveFormatPCM,
-    chunk2: WaveChunk,
+    extra_size: u16,
+    valid_bits_per_sample: u16,
+    channel_mask: u32,
+    sub_format: [u8; 16],
 }
 
-impl Detector for RiffWaveDetector {
-    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
-        if offset + size_of::<RiffWavePCMHeader>() > buffer.len() {
-            return None;
+impl WaveFormatPCM {
+    /// Converts the fields of a big-endian (RIFX) header.
+    fn from_be(format: Self) -> Self {
+        WaveFormatPCM {
+            format: WaveFormat {
+                format_tag: u16::from_be(format.format.format_tag),
+                channels: u16::from_be(format.format.channels),
+                sample_rate: u32::from_be(format.format.sample_rate),
+                byte_rate: u32::from_be(format.format.byte_rate),
+                block_align: u16::from_be(format.format.block_align),
+            },
+            bits_per_sample: u16::from_be(format.bits_per_sample),
         }
+    }
+}
 
-        let (head, body, _tail) = unsafe {
-            &buffer[offset..offset + size_of::<RiffWavePCMHeader>()].align_to::<RiffWavePCMHeader>()
+fn format_name(format_tag: u16) -> Option<&'static str> {
+    Some(match format_tag {
+        WAVE_FORMAT_PCM => "pcm",
+        WAVE_FORMAT_ADPCM => "adpcm",
+        WAVE_FORMAT_IEEE_FLOAT => "float",
+        WAVE_FORMAT_ALAW => "alaw",
+        WAVE_FORMAT_MULAW => "mulaw",
+        WAVE_FORMAT_IMA_ADPCM => "ima_adpcm",
+        _ => return None,
+    })
+}
+
+/// Format of a WAVE file.
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    format_tag: u16,
+    /// Format tag of the sub-format for WAVE_FORMAT_EXTENSIBLE
+    codec: u16,
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+/// Size of a sample frame of uncompressed audio. Samples are stored in
+/// whole bytes.
+pub fn pcm_frame_size(channels: u16, bits_per_sample: u16) -> u32 {
+    u32::from(channels) * u32::from(bits_per_sample).div_ceil(8)
+}
+
+/// Parses and checks the `fmt ` chunk data.
+fn parse_format(data: &[u8], big_endian: bool) -> Option<Format> {
+    let mut pcm_format: WaveFormatPCM = read_struct(data)?;
+
+    if big_endian {
+        pcm_format = WaveFormatPCM::from_be(pcm_format);
+    }
+
+    let WaveFormat {
+        format_tag,
+        channels,
+        sample_rate,
+        byte_rate,
+        block_align,
+    } = pcm_format.format;
+    let bits_per_sample = pcm_format.bits_per_sample;
+
+    let codec = if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        let extensible: WaveFormatExtensible = read_struct(data)?;
+        let (extra_size, valid_bits_per_sample, sub_format_tag) = if big_endian {
+            (
+                u16::from_be(extensible.extra_size),
+                u16::from_be(extensible.valid_bits_per_sample),
+                u16::from_be_bytes([extensible.sub_format[0], extensible.sub_format[1]]),
+            )
+        } else {
+            (
+                extensible.extra_size,
+                extensible.valid_bits_per_sample,
+                u16::from_le_bytes([extensible.sub_format[0], extensible.sub_format[1]]),
+            )
         };
 
-        if !head.is_empty() {
+        if extra_size < 22
+            || valid_bits_per_sample > bits_per_sample
+            || &extensible.sub_format[2..] != KSDATAFORMAT_SUFFIX
+        {
             return None;
         }
 
-        let data = &body[0];
+        sub_format_tag
+    } else {
+        format_tag
+    };
 
-        if &data.header.id != b"RIFF" {
-            return None;
-        }
+    if channels == 0 || sample_rate == 0 || block_align == 0 {
+        return None;
+    }
 
-        if &data.header.format != b"WAVE" {
-            return None;
+    match codec {
+        WAVE_FORMAT_ADPCM | WAVE_FORMAT_IMA_ADPCM => {
+            // Block sizes are encoder choices, so only the sample size is
+            // fixed
+            if bits_per_sample != 4 {
+                return None;
+            }
         }
+        _ => {
+            let valid = match codec {
+                WAVE_FORMAT_PCM => bits_per_sample > 0 && bits_per_sample <= 64,
+                WAVE_FORMAT_IEEE_FLOAT => matches!(bits_per_sample, 32 | 64),
+                WAVE_FORMAT_ALAW | WAVE_FORMAT_MULAW => bits_per_sample == 8,
+                _ => false,
+            };
 
-        if &data.chunk1.id != b"fmt " {
-            return None;
-        }
+            let frame_size = pcm_frame_size(channels, bits_per_sample);
 
-        if &data.chunk2.id != b"data" {
-            return None;
+            if !valid
+                || u32::from(block_align) != frame_size
+                || u64::from(byte_rate) != u64::from(sample_rate) * u64::from(frame_size)
+            {
+                return None;
+            }
         }
+    }
 
-        if data.pcm_format.format.format_tag != 1 {
-            return None;
-        }
+    Some(Format {
+        format_tag,
+        codec,
+        channels,
+        sample_rate,
+        bits_per_sample,
+    })
+}
 
-        if data.chunk1.size != 16 {
-            return None;
-        }
+/// Validates the WAVE chunks of a RIFF, RIFX, RF64 or Wave64 file.
+fn detect_wave(
+    buffer: &[u8],
+    offset: usize,
+    riff: Riff,
+    big_endian: bool,
+    stream_type: StreamType,
+    ext: &'static str,
+) -> Option<StreamMatch> {
+    if &riff.form != b"WAVE" {
+        return None;
+    }
 
-        if data.pcm_format.format.byte_rate
-            != data.pcm_format.format.sample_rate
-                * data.pcm_format.format.channels as u32
-                * data.pcm_format.bits_per_sample as u32
-                / 8
-        {
-            return None;
-        }
+    // `fmt ` may follow other chunks (`LIST`, `bext`, `JUNK`, ...) but has
+    // to come before `data`
+    let fmt = riff.chunks.iter().position(|x| &x.id == b"fmt ")?;
+    let data = riff.chunks.iter().position(|x| &x.id == b"data")?;
 
-        if data.pcm_format.format.block_align
-            != data.pcm_format.format.channels * data.pcm_format.bits_per_sample / 8
-        {
-            return None;
+    if fmt > data {
+        return None;
+    }
+
+    let fmt = riff.chunks[fmt];
+    let format = parse_format(buffer.get(fmt.offset..fmt.offset + fmt.size)?, big_endian)?;
+    let size = riff.end.min(buffer.len()) - offset;
+
+    Some(StreamMatch {
+        offset,
+        size,
+        ext,
+        stream_type,
+        metadata: Metadata::from([
+            ("channels", format.channels.into()),
+            ("sample_rate", format.sample_rate.into()),
+            ("bits_per_sample", format.bits_per_sample.into()),
+            ("format_tag", format.format_tag.into()),
+            ("format", format_name(format.codec)?.into()),
+        ]),
+    })
+}
+
+impl Detector for RiffWaveDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        let riff = Riff::parse(buffer, offset)?;
+        detect_wave(buffer, offset, riff, false, StreamType::RiffWave, "wav")
+    }
+}
+
+impl Detector for Rf64Detector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        let riff = Riff::parse_rf64(buffer, offset)?;
+        detect_wave(buffer, offset, riff, false, StreamType::Rf64, "wav")
+    }
+}
+
+impl Detector for Wave64Detector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        let riff = Riff::parse_wave64(buffer, offset)?;
+        detect_wave(buffer, offset, riff, false, StreamType::Wave64, "w64")
+    }
+}
+
+impl Detector for RifxDetector {
+    fn detect(&self, buffer: &[u8], offset: usize, _opts: &DetectOptions) -> Option<StreamMatch> {
+        let riff = Riff::parse_rifx(buffer, offset)?;
+        detect_wave(buffer, offset, riff, true, StreamType::Rifx, "wav")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::riff::tests::{chunk, chunk_be, riff, riff_be};
+    use crate::detector::riff::WAVE64_SIGNATURE;
+    use crate::detector::MetaValue;
+
+    /// `fmt ` chunk data with the block align and byte rate of
+    /// uncompressed samples.
+    fn fmt(format_tag: u16, channels: u16, sample_rate: u32, bits_per_sample: u16) -> Vec<u8> {
+        let frame_size = pcm_frame_size(channels, bits_per_sample);
+
+        [
+            format_tag.to_le_bytes().as_slice(),
+            &channels.to_le_bytes(),
+            &sample_rate.to_le_bytes(),
+            &(sample_rate * frame_size).to_le_bytes(),
+            &(frame_size as u16).to_le_bytes(),
+            &bits_per_sample.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    fn extensible(sub_format_tag: u16, channels: u16, bits_per_sample: u16) -> Vec<u8> {
+        [
+            fmt(WAVE_FORMAT_EXTENSIBLE, channels, 48000, bits_per_sample).as_slice(),
+            &22u16.to_le_bytes(),
+            &bits_per_sample.to_le_bytes(),
+            &3u32.to_le_bytes(),
+            &sub_format_tag.to_le_bytes(),
+            KSDATAFORMAT_SUFFIX,
+        ]
+        .concat()
+    }
+
+    fn wave(fmt: &[u8]) -> Vec<u8> {
+        riff(
+            b"WAVE",
+            &[chunk(b"fmt ", fmt), chunk(b"data", &[0x55; 1000])],
+        )
+    }
+
+    /// RF64 file whose `data` size is only in the `ds64` chunk.
+    fn rf64(signature: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let fmt = chunk(b"fmt ", &fmt(WAVE_FORMAT_PCM, 2, 44100, 16));
+        let size = 4 + 36 + fmt.len() + 8 + data.len();
+        let ds64 = [
+            (size as u64).to_le_bytes().as_slice(),
+            &(data.len() as u64).to_le_bytes(),
+            &(data.len() as u64 / 4).to_le_bytes(),
+            &0u32.to_le_bytes(),
+        ]
+        .concat();
+
+        [
+            signature.as_slice(),
+            &u32::MAX.to_le_bytes(),
+            b"WAVE",
+            &chunk(b"ds64", &ds64),
+            &fmt,
+            b"data",
+            &u32::MAX.to_le_bytes(),
+            data,
+        ]
+        .concat()
+    }
+
+    /// Wave64 chunk: a GUID starting with the RIFF id, a 64-bit size that
+    /// includes the header, and padding to 8 bytes.
+    fn w64_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let size = (24 + data.len()) as u64;
+        let pad = vec![0; data.len().next_multiple_of(8) - data.len()];
+        let guid = b"\xF3\xAC\xD3\x11\x8C\xD1\x00\xC0\x4F\x8E\xDB\x8A";
+
+        [id.as_slice(), guid, &size.to_le_bytes(), data, &pad].concat()
+    }
+
+    fn w64(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let chunks = chunks.concat();
+        let size = (40 + chunks.len()) as u64;
+        let wave = b"wave\xF3\xAC\xD3\x11\x8C\xD1\x00\xC0\x4F\x8E\xDB\x8A";
+
+        [
+            WAVE64_SIGNATURE.as_slice(),
+            &size.to_le_bytes(),
+            wave,
+            &chunks,
+        ]
+        .concat()
+    }
+
+    fn detect(file: &[u8]) -> Option<StreamMatch> {
+        let buffer = [file, &[0xAA; 100]].concat();
+        RiffWaveDetector.detect(&buffer, 0, &DetectOptions::default())
+    }
+
+    #[test]
+    fn wave_formats() {
+        let file = wave(&fmt(WAVE_FORMAT_PCM, 2, 44100, 16));
+        let m = detect(&file).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "wav");
+        assert_eq!(m.metadata["channels"], MetaValue::Int(2));
+        assert_eq!(m.metadata["sample_rate"], MetaValue::Int(44100));
+        assert_eq!(m.metadata["bits_per_sample"], MetaValue::Int(16));
+        assert_eq!(m.metadata["format"], MetaValue::from("pcm"));
+
+        let formats = [
+            (fmt(WAVE_FORMAT_PCM, 1, 8000, 24), "pcm"),
+            (fmt(WAVE_FORMAT_IEEE_FLOAT, 2, 48000, 32), "float"),
+            (fmt(WAVE_FORMAT_ALAW, 1, 8000, 8), "alaw"),
+            (fmt(WAVE_FORMAT_MULAW, 1, 8000, 8), "mulaw"),
+            (extensible(WAVE_FORMAT_PCM, 6, 24), "pcm"),
+            (extensible(WAVE_FORMAT_IEEE_FLOAT, 2, 64), "float"),
+        ];
+
+        for (fmt, format) in formats {
+            let m = detect(&wave(&fmt)).unwrap();
+            assert_eq!(m.metadata["format"], MetaValue::from(format));
         }
 
-        let mut data_size = usize::try_from(data.chunk2.size).unwrap();
-        let chunk_size = usize::try_from(data.header.chunk_size).unwrap();
+        // ADPCM block sizes are encoder choices
+        let mut adpcm = fmt(WAVE_FORMAT_IMA_ADPCM, 1, 22050, 4);
+        adpcm[8..12].copy_from_slice(&11100u32.to_le_bytes());
+        adpcm[12..14].copy_from_slice(&512u16.to_le_bytes());
+        let m = detect(&wave(&adpcm)).unwrap();
+        assert_eq!(m.metadata["format"], MetaValue::from("ima_adpcm"));
 
-        if chunk_size < data_size {
-            return None;
+        // Other chunks may come before `fmt `
+        let file = riff(
+            b"WAVE",
+            &[
+                chunk(b"JUNK", &[0; 27]),
+                chunk(b"fmt ", &fmt(WAVE_FORMAT_PCM, 2, 44100, 16)),
+                chunk(b"data", &[0x55; 1001]),
+            ],
+        );
+        assert_eq!(detect(&file).unwrap().size, file.len());
+    }
+
+    #[test]
+    fn invalid_formats_are_rejected() {
+        let invalid = [
+            // Unknown format tag
+            fmt(0x0055, 2, 44100, 16),
+            // Float samples are 32 or 64-bit
+            fmt(WAVE_FORMAT_IEEE_FLOAT, 2, 44100, 16),
+            // G.711 samples are 8-bit
+            fmt(WAVE_FORMAT_ALAW, 1, 8000, 16),
+            // No channels
+            fmt(WAVE_FORMAT_PCM, 0, 44100, 16),
+        ];
+
+        for fmt in invalid {
+            assert!(detect(&wave(&fmt)).is_none());
         }
 
-        data_size += size_of::<RiffWavePCMHeader>();
+        // Byte rate and block align that do not match the samples
+        let mut pcm = fmt(WAVE_FORMAT_PCM, 2, 44100, 16);
+        pcm[8..12].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(detect(&wave(&pcm)).is_none());
 
-        if offset + data_size > buffer.len() {
-            data_size = buffer.len() - offset;
+        let mut pcm = fmt(WAVE_FORMAT_PCM, 2, 44100, 16);
+        pcm[12..14].copy_from_slice(&3u16.to_le_bytes());
+        assert!(detect(&wave(&pcm)).is_none());
+
+        // Sub-format GUID with another suffix
+        let mut ext = extensible(WAVE_FORMAT_PCM, 2, 16);
+        ext[30] ^= 0x01;
+        assert!(detect(&wave(&ext)).is_none());
+
+        // `fmt ` after `data`
+        let file = riff(
+            b"WAVE",
+            &[
+                chunk(b"data", &[0x55; 1000]),
+                chunk(b"fmt ", &fmt(WAVE_FORMAT_PCM, 2, 44100, 16)),
+            ],
+        );
+        assert!(detect(&file).is_none());
+    }
+
+    #[test]
+    fn rf64_sizes_come_from_ds64() {
+        let opts = DetectOptions::default();
+
+        for signature in [b"RF64", b"BW64"] {
+            let file = rf64(signature, &[0x55; 1000]);
+            let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+            let m = Rf64Detector.detect(&buffer, 0, &opts).unwrap();
+            assert_eq!(m.size, file.len());
+            assert_eq!(m.ext, "wav");
+            assert_eq!(m.stream_type, StreamType::Rf64);
+            assert_eq!(m.metadata["format"], MetaValue::from("pcm"));
         }
 
-        return Some(StreamMatch {
-            offset,
-            size: data_size,
-            ext: "wav",
-        });
+        // `ds64` has to be the first chunk
+        let mut file = rf64(b"RF64", &[0x55; 1000]);
+        file[12..16].copy_from_slice(b"JUNK");
+        assert!(Rf64Detector.detect(&file, 0, &opts).is_none());
+
+        // A data size past the file size
+        let mut file = rf64(b"RF64", &[0x55; 1000]);
+        file[28..36].copy_from_slice(&100_000u64.to_le_bytes());
+        assert!(Rf64Detector.detect(&file, 0, &opts).is_none());
+    }
+
+    #[test]
+    fn wave64_chunks_are_walked() {
+        let opts = DetectOptions::default();
+        let file = w64(&[
+            w64_chunk(b"fmt ", &fmt(WAVE_FORMAT_PCM, 1, 22050, 8)),
+            w64_chunk(b"data", &[0x55; 1001]),
+        ]);
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = Wave64Detector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.ext, "w64");
+        assert_eq!(m.metadata["sample_rate"], MetaValue::Int(22050));
+
+        // A chunk size that does not include the header
+        let mut file = file.clone();
+        file[40 + 16..40 + 24].copy_from_slice(&8u64.to_le_bytes());
+        assert!(Wave64Detector.detect(&file, 0, &opts).is_none());
+
+        // Another form type
+        let mut file = w64(&[w64_chunk(b"data", &[0x55; 100])]);
+        file[24] = b'W';
+        assert!(Wave64Detector.detect(&file, 0, &opts).is_none());
+    }
+
+    #[test]
+    fn rifx_is_big_endian() {
+        let opts = DetectOptions::default();
+        let be_fmt = [
+            WAVE_FORMAT_PCM.to_be_bytes().as_slice(),
+            &2u16.to_be_bytes(),
+            &44100u32.to_be_bytes(),
+            &176400u32.to_be_bytes(),
+            &4u16.to_be_bytes(),
+            &16u16.to_be_bytes(),
+        ]
+        .concat();
+        let file = riff_be(
+            b"RIFX",
+            b"WAVE",
+            &[chunk_be(b"fmt ", &be_fmt), chunk_be(b"data", &[0x55; 1000])],
+        );
+        let buffer = [file.as_slice(), &[0xAA; 100]].concat();
+
+        let m = RifxDetector.detect(&buffer, 0, &opts).unwrap();
+        assert_eq!(m.size, file.len());
+        assert_eq!(m.stream_type, StreamType::Rifx);
+        assert_eq!(m.metadata["channels"], MetaValue::Int(2));
+        assert_eq!(m.metadata["sample_rate"], MetaValue::Int(44100));
+
+        // Little-endian fields in a RIFX file
+        let file = riff_be(
+            b"RIFX",
+            b"WAVE",
+            &[
+                chunk_be(b"fmt ", &fmt(WAVE_FORMAT_PCM, 2, 44100, 16)),
+                chunk_be(b"data", &[0x55; 1000]),
+            ],
+        );
+        assert!(RifxDetector.detect(&file, 0, &opts).is_none());
     }
 }
@@ -0,0 +1,246 @@
+//! Metadata tags stored around MPEG audio streams.
+
+use super::bytes::read_u32_le;
+use memchr::memmem;
+
+pub const ID3V2_SIGNATURE: &[u8; 3] = b"ID3";
+
+const ID3V2_HEADER_SIZE: usize = 10;
+const ID3V1_SIZE: usize = 128;
+/// Enhanced ID3v1 block, stored before the ID3v1 tag
+const ID3V1_ENHANCED_SIZE: usize = 227;
+
+const APE_PREAMBLE: &[u8; 8] = b"APETAGEX";
+const APE_HEADER_SIZE: usize = 32;
+/// Flag of an APEv2 header (as opposed to a footer)
+const APE_IS_HEADER: u32 = 1 << 29;
+/// Largest APE tag walked when it has no header
+const MAX_APE_SIZE: usize = 16 * 1024 * 1024;
+/// APE item keys are 2 to 255 characters long
+const MAX_APE_KEY_LENGTH: usize = 255;
+
+const LYRICS_BEGIN: &[u8; 11] = b"LYRICSBEGIN";
+const LYRICS_END_V1: &[u8; 9] = b"LYRICSEND";
+const LYRICS_END_V2: &[u8; 9] = b"LYRICS200";
+/// Lyrics3 v1 holds up to 5100 bytes of lyrics
+const MAX_LYRICS3V1_SIZE: usize = 11 + 5100 + 9;
+/// Lyrics3 v2 sizes have 6 decimal digits
+const MAX_LYRICS3V2_SIZE: usize = 999_999 + 6 + 9;
+
+/// Decodes a synchsafe integer (7 bits per byte).
+fn synchsafe(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .iter()
+        .try_fold(0, |acc, &b| (b < 0x80).then_some(acc << 7 | usize::from(b)))
+}
+
+/// Size of the ID3v2 tag at the start of `bytes` (header, frames, padding
+/// and footer).
+pub fn id3v2_size(bytes: &[u8]) -> Option<usize> {
+    let header = bytes.get(..ID3V2_HEADER_SIZE)?;
+
+    // Major versions 2.2 to 2.4; revisions are never 0xFF
+    if !header.starts_with(ID3V2_SIGNATURE) || !(2..=4).contains(&header[3]) || header[4] == 0xFF {
+        return None;
+    }
+
+    let size = synchsafe(&header[6..10])?;
+    let footer = if header[3] == 4 && header[5] & 0x10 != 0 {
+        ID3V2_HEADER_SIZE
+    } else {
+        0
+    };
+
+    Some(ID3V2_HEADER_SIZE + size + footer)
+}
+
+/// APEv2 tag at the start of `bytes`. With a header its size is known
+/// right away; without one the items are walked to the footer, which has
+/// to count the bytes back to the start.
+fn ape_size(bytes: &[u8]) -> Option<usize> {
+    let tag_size = |footer: &[u8]| read_u32_le(footer, 12) as usize;
+
+    if bytes.starts_with(APE_PREAMBLE) {
+        let Some(header) = bytes.get(..APE_HEADER_SIZE) else {
+            return Some(bytes.len());
+        };
+
+        let flags = read_u32_le(header, 20);
+
+        // The size counts the items and the footer
+        if flags & APE_IS_HEADER != 0 {
+            return Some(APE_HEADER_SIZE + tag_size(header));
+        }
+    }
+
+    // Items: value size (4), flags (4), key (ASCII, NUL-terminated), value
+    let mut offset = 0;
+
+    while !bytes.get(offset..)?.starts_with(APE_PREAMBLE) {
+        let value_size = read_u32_le(bytes.get(offset..offset + 8)?, 0) as usize;
+        let key = bytes.get(offset + 8..)?;
+        let key_len = memchr::memchr(0, &key[..key.len().min(MAX_APE_KEY_LENGTH + 1)])?;
+
+        if key_len < 2 || !key[..key_len].iter().all(|x| (0x20..=0x7E).contains(x)) {
+            return None;
+        }
+
+        offset = (offset + 8 + key_len + 1).checked_add(value_size)?;
+
+        if offset > MAX_APE_SIZE {
+            return None;
+        }
+    }
+
+    let footer = bytes.get(offset..offset + APE_HEADER_SIZE)?;
+
+    (offset + APE_HEADER_SIZE == tag_size(footer)).then_some(tag_size(footer))
+}
+
+/// Lyrics3 tag at the start of `bytes`. v2 tags end with their size in 6
+/// digits and `LYRICS200`, v1 tags with `LYRICSEND`.
+fn lyrics3_size(bytes: &[u8]) -> Option<usize> {
+    if !bytes.starts_with(LYRICS_BEGIN) {
+        return None;
+    }
+
+    let window = &bytes[..bytes.len().min(MAX_LYRICS3V2_SIZE)];
+
+    for end in memmem::find_iter(window, LYRICS_END_V2) {
+        let Some(start) = end.checked_sub(6) else {
+            continue;
+        };
+
+        let digits = std::str::from_utf8(&window[start..end]).ok();
+
+        if digits.and_then(|x| x.parse().ok()) == Some(start) {
+            return Some(end + LYRICS_END_V2.len());
+        }
+    }
+
+    let window = &bytes[..bytes.len().min(MAX_LYRICS3V1_SIZE)];
+    let end = memmem::find(window, LYRICS_END_V1)?;
+
+    Some(end + LYRICS_END_V1.len())
+}
+
+/// Name and size of a tag at the start of `bytes` that may follow the
+/// last audio frame (ID3v1, APEv2 or Lyrics3). The size may reach past the
+/// end of `bytes` when the tag is cut.
+pub fn trailing_tag(bytes: &[u8]) -> Option<(&'static str, usize)> {
+    if bytes.starts_with(b"TAG+")
+        && bytes
+            .get(ID3V1_ENHANCED_SIZE..)
+            .is_some_and(|x| x.starts_with(b"TAG"))
+    {
+        return Some(("id3v1", ID3V1_ENHANCED_SIZE + ID3V1_SIZE));
+    }
+
+    if bytes.starts_with(b"TAG") {
+        return Some(("id3v1", ID3V1_SIZE));
+    }
+
+    if let Some(size) = lyrics3_size(bytes) {
+        return Some(("lyrics3", size));
+    }
+
+    ape_size(bytes).map(|size| ("apev2", size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// APEv2 header or footer for a tag with `items_size` bytes of items.
+    fn ape_header(items_size: usize, items: u32, is_header: bool) -> Vec<u8> {
+        let flags = 1 << 31 | if is_header { APE_IS_HEADER } else { 0 };
+
+        [
+            APE_PREAMBLE.as_slice(),
+            &2000u32.to_le_bytes(),
+            &(items_size as u32 + 32).to_le_bytes(),
+            &items.to_le_bytes(),
+            &flags.to_le_bytes(),
+            &[0; 8],
+        ]
+        .concat()
+    }
+
+    fn ape_item(key: &str, value: &[u8]) -> Vec<u8> {
+        [
+            &(value.len() as u32).to_le_bytes(),
+            [0; 4].as_slice(),
+            key.as_bytes(),
+            &[0],
+            value,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn id3v2_sizes() {
+        assert_eq!(
+            id3v2_size(b"ID3\x03\x00\x00\x00\x00\x02\x01"),
+            Some(10 + 257)
+        );
+        // v2.4 footer
+        assert_eq!(
+            id3v2_size(b"ID3\x04\x00\x10\x00\x00\x00\x10"),
+            Some(10 + 16 + 10)
+        );
+        // Not synchsafe
+        assert_eq!(id3v2_size(b"ID3\x03\x00\x00\x00\x00\x00\x80"), None);
+        // Unknown version
+        assert_eq!(id3v2_size(b"ID3\x05\x00\x00\x00\x00\x00\x10"), None);
+    }
+
+    #[test]
+    fn ape_tags_with_and_without_header() {
+        let items = [ape_item("Title", b"Song"), ape_item("Artist", b"Band")].concat();
+        let footer = ape_header(items.len(), 2, false);
+
+        let tag = [
+            ape_header(items.len(), 2, true),
+            items.clone(),
+            footer.clone(),
+        ]
+        .concat();
+        let bytes = [tag.as_slice(), b"TAG"].concat();
+        assert_eq!(trailing_tag(&bytes), Some(("apev2", tag.len())));
+
+        // The footer is found by walking the items
+        let tag = [items.clone(), footer.clone()].concat();
+        let bytes = [tag.as_slice(), b"TAG"].concat();
+        assert_eq!(trailing_tag(&bytes), Some(("apev2", tag.len())));
+
+        // Empty tag
+        let tag = ape_header(0, 0, false);
+        assert_eq!(trailing_tag(&tag), Some(("apev2", 32)));
+
+        // A footer that does not count back to the start
+        let tag = [items.as_slice(), &[0; 4], &footer].concat();
+        assert_eq!(trailing_tag(&tag), None);
+
+        // Data that is not an item is not searched for a footer
+        let bytes = [[0xFF; 64].as_slice(), &items, &footer].concat();
+        assert_eq!(trailing_tag(&bytes), None);
+    }
+
+    #[test]
+    fn id3v1_and_lyrics3_tags() {
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0);
+        assert_eq!(trailing_tag(&id3v1), Some(("id3v1", 128)));
+
+        let enhanced = [b"TAG+".as_slice(), &[0; 223], &id3v1].concat();
+        assert_eq!(trailing_tag(&enhanced), Some(("id3v1", 355)));
+
+        let lyrics3v2 = b"LYRICSBEGININD0000210000021LYRICS200";
+        assert_eq!(trailing_tag(lyrics3v2), Some(("lyrics3", lyrics3v2.len())));
+
+        let lyrics3v1 = b"LYRICSBEGINsome lyricsLYRICSEND";
+        assert_eq!(trailing_tag(lyrics3v1), Some(("lyrics3", lyrics3v1.len())));
+
+        assert_eq!(trailing_tag(b"not a tag"), None);
+    }
+}
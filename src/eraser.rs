@@ -1,17 +1,23 @@
 use memmap2::MmapMut;
 use range_set_blaze::RangeSetBlaze;
-use std::fs::File;
+use std::fs::{remove_file, File, OpenOptions};
+use std::path::{Path, PathBuf};
 
-pub fn erase_sectors(file: &File, sectors: &RangeSetBlaze<usize>) -> std::io::Result<usize> {
-    let mut mmap = unsafe { MmapMut::map_mut(file)? };
+use crate::error::{Error, Result};
+use crate::injector::{copy_io, inject_io};
+use crate::journal::{Journal, JournalRange};
+use crate::manifest::{hash_range, ManifestEntry, SourceInfo};
+
+pub fn erase_sectors(file: &File, sectors: &RangeSetBlaze<usize>) -> Result<usize> {
+    let mut mmap = unsafe { MmapMut::map_mut(file).map_err(Error::Mmap)? };
 
     let mut total_bytes_written = 0;
     let mut buffer_size = 128 * 1024;
 
     for r in sectors.ranges() {
         let mut bytes_written = 0;
-        let size = r.end() - r.start();
-        let start: usize = r.start().clone();
+        let size = r.end() - r.start() + 1;
+        let start: usize = *r.start();
 
         while bytes_written < size {
             if bytes_written + buffer_size > size {
@@ -26,5 +32,115 @@ pub fn erase_sectors(file: &File, sectors: &RangeSetBlaze<usize>) -> std::io::Re
     }
 
     mmap.flush()?;
-    return Ok(total_bytes_written);
+    Ok(total_bytes_written)
+}
+
+/// Writes the undo journal of `sectors` next to `file_path`, then zeroes
+/// them in `file`. `buffer` is the mapped content of `file`; `sha256` is
+/// its hash before erasing. Returns the number of erased bytes.
+pub fn erase(
+    file_path: &Path,
+    file: &File,
+    buffer: &[u8],
+    sectors: &RangeSetBlaze<usize>,
+    streams_dir: Option<PathBuf>,
+    streams: Vec<ManifestEntry>,
+    sha256: Option<String>,
+) -> Result<usize> {
+    let mut source = SourceInfo::from_file(file_path, file)?;
+    source.sha256 = sha256;
+
+    Journal::new(source, sectors, buffer, streams_dir, streams).write(file_path, buffer)?;
+
+    erase_sectors(file, sectors)
+}
+
+/// Progress of [`unerase`].
+pub enum UneraseEvent<'a> {
+    /// The file of an extracted stream is missing from the streams folder
+    Missing(&'a ManifestEntry),
+    /// The range has its original hash again
+    Restored(&'a JournalRange),
+    /// The range still differs from the original bytes
+    Failed(&'a JournalRange),
+}
+
+#[derive(Debug, Default)]
+pub struct UneraseReport {
+    pub restored: usize,
+    pub failed: usize,
+}
+
+/// Restores the ranges recorded in the journal of `file_path`, from the
+/// journal itself or from the extracted streams (in `input_dir`, or the
+/// folder recorded in the journal). The journal is removed once every
+/// range is restored.
+pub fn unerase(
+    file_path: &Path,
+    input_dir: Option<&Path>,
+    mut on_event: impl FnMut(UneraseEvent),
+) -> Result<UneraseReport> {
+    let (journal, data_start) = Journal::read(file_path)?;
+    let journal_file = File::open(Journal::path(file_path))?;
+
+    let dst = OpenOptions::new().write(true).read(true).open(file_path)?;
+    let dst_size = dst.metadata()?.len();
+
+    if dst_size != journal.source.size {
+        return Err(Error::Validation(format!(
+            "file size ({}) does not match the size recorded in the journal ({})",
+            dst_size, journal.source.size
+        )));
+    }
+
+    let streams_dir = input_dir.or(journal.streams_dir.as_deref());
+    let mut report = UneraseReport::default();
+
+    for range in &journal.ranges {
+        if let Some(data_offset) = range.data_offset {
+            copy_io(
+                &journal_file,
+                data_start + data_offset,
+                &dst,
+                range.offset,
+                range.size,
+            )?;
+        } else {
+            let streams_dir = streams_dir.ok_or_else(|| {
+                Error::Validation("folder with the extracted streams is not set".to_string())
+            })?;
+
+            let streams = journal.streams.iter().filter(|x| {
+                x.offset < range.offset + range.size && x.offset + x.size > range.offset
+            });
+
+            for entry in streams {
+                match entry.resolve(streams_dir) {
+                    Ok(Some(path)) => {
+                        inject_io(&File::open(&path)?, &dst, entry.offset)?;
+                    }
+                    _ => on_event(UneraseEvent::Missing(entry)),
+                }
+            }
+        }
+
+        match hash_range(&dst, range.offset, range.size) {
+            Ok(hash) if hash == range.sha256 => {
+                report.restored += 1;
+                on_event(UneraseEvent::Restored(range));
+            }
+            _ => {
+                report.failed += 1;
+                on_event(UneraseEvent::Failed(range));
+            }
+        }
+    }
+
+    dst.sync_all()?;
+
+    if report.failed == 0 {
+        remove_file(Journal::path(file_path))?;
+    }
+
+    Ok(report)
 }
@@ -0,0 +1,75 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::thread;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed
+    Io(std::io::Error),
+    /// Memory-mapping a file failed
+    Mmap(std::io::Error),
+    /// The signature patterns could not be compiled
+    Pattern(aho_corasick::BuildError),
+    /// The manifest is missing, malformed or could not be written
+    Manifest(PathBuf, std::io::Error),
+    /// The undo journal is missing, malformed or could not be written
+    Journal(PathBuf, std::io::Error),
+    /// The input does not match what was expected (size, hash, etc.)
+    Validation(String),
+}
+
+impl Error {
+    /// Returns the underlying I/O error, if any.
+    pub fn io_error(&self) -> Option<&std::io::Error> {
+        match self {
+            Error::Io(err) | Error::Mmap(err) => Some(err),
+            Error::Manifest(_, err) | Error::Journal(_, err) => Some(err),
+            Error::Pattern(_) | Error::Validation(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Mmap(err) => write!(f, "failed to mmap the file: {}", err),
+            Error::Pattern(err) => write!(f, "failed to compile patterns: {}", err),
+            Error::Manifest(path, err) => write!(f, "manifest {}: {}", path.display(), err),
+            Error::Journal(path, err) => write!(f, "journal {}: {}", path.display(), err),
+            Error::Validation(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Pattern(err) => Some(err),
+            _ => self
+                .io_error()
+                .map(|err| err as &(dyn std::error::Error + 'static)),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<aho_corasick::BuildError> for Error {
+    fn from(err: aho_corasick::BuildError) -> Self {
+        Error::Pattern(err)
+    }
+}
+
+/// Joins a worker thread, turning its panic into an error.
+pub(crate) fn join<T>(handle: thread::JoinHandle<Result<T>>, name: &str) -> Result<T> {
+    handle
+        .join()
+        .map_err(|_| Error::Io(std::io::Error::other(format!("{} thread panicked", name))))?
+}
@@ -1,44 +1,324 @@
-use memmap2::MmapMut;
-use std::fs::OpenOptions;
-use std::path::PathBuf;
-
-pub fn extract(
-    buffer: &[u8],
-    offset: usize,
-    size: usize,
-    ext: &str,
-    output_dir: &PathBuf,
-) -> std::io::Result<usize> {
-    let file_name = format!("{}.{}", offset, ext);
-    let output_path = output_dir.as_path().join(file_name);
-
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&output_path)?;
-
-    file.set_len(size as u64)?;
-
-    let mut mmap = unsafe { MmapMut::map_mut(&file)? };
-
-    let mut bytes_written = 0;
-    let mut buffer_size = 128 * 1024;
-
-    while bytes_written < size {
-        if bytes_written + buffer_size > size {
-            buffer_size = size - bytes_written;
+use memmap2::Mmap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::detector::{StreamMatch, StreamType};
+use crate::eraser;
+use crate::error::{join, Error, Result};
+use crate::injector::is_mmap_support;
+use crate::journal::Journal;
+use crate::manifest::{hash_bytes, Manifest, ManifestEntry, SourceInfo};
+use crate::scanner::Scanner;
+
+/// Found streams waiting to be written by the extractor thread
+const EXTRACT_QUEUE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Folder the found streams are written to, `None` to only scan
+    pub output_dir: Option<PathBuf>,
+    /// Zero the found streams in the input after writing an undo journal
+    pub erase_sectors: bool,
+    /// Size of the sliding window for inputs that cannot be mapped
+    pub window_size: usize,
+}
+
+pub struct Summary {
+    pub process_time: Duration,
+    pub processed_bytes: usize,
+    pub total_streams_size: usize,
+    pub total_streams_count: usize,
+    pub by_type: BTreeMap<StreamType, TypeStats>,
+    /// Streams that reached the end of the read window and may be cut
+    pub truncated_streams: usize,
+    /// Bytes zeroed in the input (only set when sectors were erased)
+    pub erased_bytes: Option<usize>,
+}
+
+/// Per-format breakdown of the found streams.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TypeStats {
+    pub count: usize,
+    pub total_size: usize,
+    pub min_size: usize,
+    pub max_size: usize,
+    /// Percentage of the input covered by the streams of this type
+    pub coverage_percent: f64,
+    /// Candidate offsets the detector looked at
+    pub candidates: u64,
+    /// Candidate offsets the detector accepted
+    pub accepted: u64,
+}
+
+impl TypeStats {
+    pub fn add(&mut self, size: usize) {
+        self.min_size = if self.count == 0 {
+            size
+        } else {
+            self.min_size.min(size)
+        };
+
+        self.max_size = self.max_size.max(size);
+        self.total_size += size;
+        self.count += 1;
+    }
+}
+
+pub fn file_name(offset: usize, ext: &str) -> String {
+    format!("{}.{}", offset, ext)
+}
+
+/// Writes the `bytes` of the stream found at `offset`. The file is
+/// written rather than mapped, so a full disk is an I/O error instead of
+/// a bus error on a sparse mapping.
+pub fn extract(bytes: &[u8], offset: usize, ext: &str, output_dir: &Path) -> Result<usize> {
+    let output_path = output_dir.join(file_name(offset, ext));
+
+    let mut writer = BufWriter::new(File::create(&output_path)?);
+    writer.write_all(bytes)?;
+    writer.flush()?;
+
+    Ok(bytes.len())
+}
+
+/// A found stream sent to the extractor thread, with its bytes when the
+/// input is read as a stream (there is no mapped buffer to copy from).
+type Extracted = (StreamMatch, Option<Vec<u8>>);
+
+struct State<'a, F> {
+    on_match: &'a mut F,
+    is_extract: bool,
+    total_streams_size: usize,
+    total_streams_count: usize,
+    by_type: BTreeMap<StreamType, TypeStats>,
+}
+
+/// Breaks when the extractor has stopped (it failed).
+fn handle_match<F: FnMut(&StreamMatch)>(
+    m: &StreamMatch,
+    bytes: Option<&[u8]>,
+    extractor: &mpsc::SyncSender<Extracted>,
+    state: &mut State<F>,
+) -> ControlFlow<()> {
+    state.total_streams_count += 1;
+    state.total_streams_size += m.size;
+    state.by_type.entry(m.stream_type).or_default().add(m.size);
+
+    if state.is_extract
+        && extractor
+            .send((m.clone(), bytes.map(<[u8]>::to_vec)))
+            .is_err()
+    {
+        return ControlFlow::Break(());
+    }
+
+    (state.on_match)(m);
+
+    ControlFlow::Continue(())
+}
+
+/// Source info for the manifest/journal. Inputs that are not regular files
+/// (stdin, pipes) have no meaningful size or mtime of their own.
+fn source_info(file_path: &Path, file: Option<&File>, size: usize) -> Result<SourceInfo> {
+    match file {
+        Some(file) if file.metadata()?.is_file() => Ok(SourceInfo::from_file(file_path, file)?),
+        _ => Ok(SourceInfo::from_stream(file_path, size as u64)),
+    }
+}
+
+/// Scans `file_path` (`-` for stdin) and, when `options.output_dir` is
+/// set, extracts the found streams there along with a manifest.
+/// `on_match` is called for every found stream in offset order.
+pub fn extract_file(
+    file_path: &Path,
+    scanner: &Scanner,
+    options: &ExtractOptions,
+    mut on_match: impl FnMut(&StreamMatch),
+) -> Result<Summary> {
+    let is_stdin = file_path.as_os_str() == "-";
+
+    let file = match is_stdin {
+        true => None,
+        false => Some(
+            OpenOptions::new()
+                .write(options.erase_sectors)
+                .read(true)
+                .open(file_path)?,
+        ),
+    };
+
+    // Pipes, stdin and files larger than the address space are read
+    // through a sliding window instead
+    let is_mapped = match &file {
+        Some(file) => file.metadata()?.is_file() && is_mmap_support(file)?,
+        None => false,
+    };
+
+    if options.erase_sectors && !is_mapped {
+        return Err(Error::Validation(
+            "--erase-sectors requires a regular file that can be memory-mapped".to_string(),
+        ));
+    }
+
+    if options.erase_sectors && Journal::path(file_path).exists() {
+        return Err(Error::Validation(format!(
+            "journal {} already exists, run \"unerase\" or remove it first",
+            Journal::path(file_path).display()
+        )));
+    }
+
+    let mmap = match (&file, is_mapped) {
+        (Some(file), true) => Some(Arc::new(unsafe { Mmap::map(file).map_err(Error::Mmap)? })),
+        _ => None,
+    };
+
+    if let Some(output_dir) = &options.output_dir {
+        create_dir_all(output_dir)?;
+    }
+
+    let output_dir_cloned = options.output_dir.clone().unwrap_or_default();
+
+    let start_time = Instant::now();
+    let (esx, erx) = mpsc::sync_channel::<Extracted>(EXTRACT_QUEUE_SIZE);
+
+    let mmap_cloned = mmap.clone();
+
+    let extractor = thread::spawn(move || {
+        let mut entries = Vec::new();
+
+        for (m, bytes) in erx {
+            let StreamMatch {
+                offset,
+                size,
+                ext,
+                stream_type,
+                ..
+            } = m;
+
+            // The window passes the bytes along, a mapped input has them
+            let bytes = match (&bytes, &mmap_cloned) {
+                (Some(bytes), _) => bytes.as_slice(),
+                (None, Some(mmap)) => &mmap[offset..offset + size],
+                (None, None) => unreachable!("stream bytes are not available"),
+            };
+
+            extract(bytes, offset, ext, &output_dir_cloned)?;
+
+            entries.push(ManifestEntry {
+                file: file_name(offset, ext),
+                offset: offset as u64,
+                size: size as u64,
+                stream_type,
+                ext: ext.to_string(),
+                sha256: hash_bytes(bytes),
+            });
+        }
+
+        Ok::<_, Error>(entries)
+    });
+
+    let mut state = State {
+        on_match: &mut on_match,
+        total_streams_size: 0,
+        total_streams_count: 0,
+        is_extract: options.output_dir.is_some(),
+        by_type: BTreeMap::new(),
+    };
+
+    let scan_stats = match (&mmap, &file) {
+        (Some(mmap), _) => scanner.scan_with(mmap, |m| handle_match(m, None, &esx, &mut state)),
+        (None, Some(file)) => scanner.scan_reader(file, options.window_size, |m, bytes| {
+            handle_match(m, Some(bytes), &esx, &mut state)
+        })?,
+        (None, None) => {
+            scanner.scan_reader(io::stdin().lock(), options.window_size, |m, bytes| {
+                handle_match(m, Some(bytes), &esx, &mut state)
+            })?
         }
+    };
 
-        let start = offset + bytes_written;
-        let end = start + buffer_size;
-        let dst = &mut mmap[bytes_written..bytes_written + buffer_size];
+    for (stream_type, detector_stats) in scan_stats.detector_stats {
+        let stats = state.by_type.entry(stream_type).or_default();
+        stats.candidates = detector_stats.candidates;
+        stats.accepted = detector_stats.accepted;
+    }
 
-        dst.copy_from_slice(&buffer[start..end]);
-        bytes_written += buffer_size;
+    for stats in state.by_type.values_mut() {
+        stats.coverage_percent =
+            stats.total_size as f64 * 100.0 / scan_stats.scanned_bytes.max(1) as f64;
     }
+    drop(esx);
+
+    let mut entries = join(extractor, "extractor")?;
+    let mut original_sha256 = None;
+    let mut erased_bytes = None;
+
+    entries.sort_by_key(|x| x.offset);
 
-    mmap.flush()?;
+    if let (true, Some(file), Some(mmap)) = (options.erase_sectors, &file, &mmap) {
+        original_sha256 = Some(hash_bytes(mmap));
 
-    return Ok(bytes_written);
+        let streams_dir = options
+            .output_dir
+            .as_ref()
+            .map(|dir| dir.canonicalize().unwrap_or(dir.clone()));
+
+        erased_bytes = Some(eraser::erase(
+            file_path,
+            file,
+            mmap,
+            &scan_stats.processed_sectors,
+            streams_dir,
+            entries.clone(),
+            original_sha256.clone(),
+        )?);
+    }
+
+    if let Some(output_dir) = &options.output_dir {
+        let mut source = source_info(file_path, file.as_ref(), scan_stats.scanned_bytes)?;
+        source.sha256 = original_sha256;
+
+        let manifest = Manifest {
+            source,
+            streams: entries,
+        };
+
+        manifest.write(output_dir)?;
+    }
+
+    Ok(Summary {
+        processed_bytes: scan_stats.scanned_bytes,
+        process_time: start_time.elapsed(),
+        total_streams_size: state.total_streams_size,
+        total_streams_count: state.total_streams_count,
+        by_type: state.by_type,
+        truncated_streams: scan_stats.truncated_streams,
+        erased_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn type_stats_add() {
+        let mut stats = TypeStats::default();
+
+        for size in [300, 100, 200] {
+            stats.add(size);
+        }
+
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.total_size, 600);
+        assert_eq!(stats.min_size, 100);
+        assert_eq!(stats.max_size, 300);
+    }
 }
@@ -1,19 +1,20 @@
 use memmap2::{Mmap, MmapMut};
-use std::fs::File;
+use std::fs::{remove_file, File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::error::{join, Error, Result};
+use crate::journal::Journal;
+use crate::manifest::{hash_file, hash_range, Manifest, SourceInfo};
 
 pub fn is_mmap_support(file: &File) -> std::io::Result<bool> {
     let size = file.metadata()?.len();
-    return Ok(!(std::mem::size_of::<usize>() < 8 && size > isize::MAX as u64));
+    Ok(!(std::mem::size_of::<usize>() < 8 && size > isize::MAX as u64))
 }
 
 pub fn inject_io(src: &File, dst: &File, offset: u64) -> std::io::Result<u64> {
-    let mut reader = BufReader::new(src);
-    let mut writer = BufWriter::new(dst);
-
-    let mut buffer_size: u64 = 128 * 1024;
-    let mut buffer = vec![0; buffer_size as usize];
-    let mut bytes_written: u64 = 0;
     let size = src.metadata()?.len();
     let dst_size = dst.metadata()?.len();
 
@@ -26,6 +27,26 @@ pub fn inject_io(src: &File, dst: &File, offset: u64) -> std::io::Result<u64> {
         ));
     }
 
+    copy_io(src, 0, dst, offset, size)
+}
+
+/// Copies `size` bytes from `src` (starting at `src_offset`) to `dst`
+/// (starting at `offset`).
+pub fn copy_io(
+    src: &File,
+    src_offset: u64,
+    dst: &File,
+    offset: u64,
+    size: u64,
+) -> std::io::Result<u64> {
+    let mut reader = BufReader::new(src);
+    let mut writer = BufWriter::new(dst);
+
+    let mut buffer_size: u64 = 128 * 1024;
+    let mut buffer = vec![0; buffer_size as usize];
+    let mut bytes_written: u64 = 0;
+
+    reader.seek(SeekFrom::Start(src_offset))?;
     writer.seek(SeekFrom::Start(offset))?;
 
     while bytes_written < size {
@@ -39,11 +60,13 @@ pub fn inject_io(src: &File, dst: &File, offset: u64) -> std::io::Result<u64> {
         bytes_written += buffer_size;
     }
 
-    return Ok(bytes_written);
+    writer.flush()?;
+
+    Ok(bytes_written)
 }
 
-pub fn inject_mmap(src: &File, dst: &mut MmapMut, offset: usize) -> std::io::Result<usize> {
-    let mmap_src = unsafe { Mmap::map(src)? };
+pub fn inject_mmap(src: &File, dst: &mut MmapMut, offset: usize) -> Result<usize> {
+    let mmap_src = unsafe { Mmap::map(src).map_err(Error::Mmap)? };
 
     let mut bytes_written = 0;
     let mut buffer_size = 128 * 1024;
@@ -53,10 +76,7 @@ pub fn inject_mmap(src: &File, dst: &mut MmapMut, offset: usize) -> std::io::Res
     if size > dst_size {
         let err_msg = format!("Size {} exceeds destination size {}", size, dst_size);
 
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::UnexpectedEof,
-            err_msg,
-        ));
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, err_msg).into());
     }
 
     while bytes_written < size {
@@ -74,5 +94,184 @@ pub fn inject_mmap(src: &File, dst: &mut MmapMut, offset: usize) -> std::io::Res
         bytes_written += buffer_size;
     }
 
-    return Ok(bytes_written);
+    Ok(bytes_written)
+}
+
+/// Progress of [`inject`].
+pub enum InjectEvent {
+    /// The file was modified after the extraction
+    Modified {
+        mtime: u64,
+        expected: u64,
+    },
+    /// The stream file is missing, the stream is skipped
+    Missing(String),
+    /// The stream file differs from the extracted stream, it is skipped
+    HashMismatch(PathBuf),
+    Injected {
+        offset: u64,
+        size: u64,
+    },
+    /// Fewer bytes than the stream file holds were injected
+    SizeMismatch {
+        injected: u64,
+        expected: u64,
+    },
+    /// The erase journal is kept, not every erased range was restored
+    JournalKept(PathBuf),
+}
+
+/// Writes the streams listed in the manifest of `input_dir` back into
+/// `file_path`. With `remove_injected` the injected stream files and the
+/// manifest are removed. The erase journal of `file_path` is removed once
+/// every erased range is restored.
+pub fn inject(
+    file_path: &Path,
+    input_dir: &Path,
+    remove_injected: bool,
+    mut on_event: impl FnMut(InjectEvent),
+) -> Result<()> {
+    let manifest = Manifest::read(input_dir)?;
+
+    let dst = OpenOptions::new().write(true).read(true).open(file_path)?;
+    let dst_info = SourceInfo::from_file(file_path, &dst)?;
+
+    if dst_info.size != manifest.source.size {
+        return Err(Error::Validation(format!(
+            "file size ({}) does not match the size recorded in the manifest ({})",
+            dst_info.size, manifest.source.size
+        )));
+    }
+
+    if dst_info.mtime != manifest.source.mtime {
+        on_event(InjectEvent::Modified {
+            mtime: dst_info.mtime,
+            expected: manifest.source.mtime,
+        });
+    }
+
+    let (sx, rx) = mpsc::channel();
+    let (evx, evrx) = mpsc::channel();
+    let walker_evx = evx.clone();
+    let input_dir_cloned = input_dir.to_path_buf();
+
+    let mut mmap_dst = None;
+
+    if let Ok(true) = is_mmap_support(&dst) {
+        mmap_dst = Some(unsafe { MmapMut::map_mut(&dst).map_err(Error::Mmap)? });
+    }
+
+    let walker = thread::spawn(move || {
+        let mut skipped = 0;
+
+        for entry in &manifest.streams {
+            let path = match entry.resolve(&input_dir_cloned) {
+                Ok(Some(path)) => path,
+                _ => {
+                    let _ = walker_evx.send(InjectEvent::Missing(entry.file.clone()));
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            let hash = File::open(&path).and_then(|f| hash_file(&f))?;
+
+            if hash != entry.sha256 {
+                let _ = walker_evx.send(InjectEvent::HashMismatch(path));
+                skipped += 1;
+                continue;
+            }
+
+            if sx.send((path, entry.offset)).is_err() {
+                break;
+            }
+        }
+
+        Ok::<_, Error>(skipped)
+    });
+
+    let injector = thread::spawn(move || {
+        let mut failed = 0;
+
+        for (path, offset) in rx {
+            let src = OpenOptions::new().read(true).open(&path)?;
+
+            let mut injected_bytes: u64 = 0;
+            let mut mmap_injected = false;
+
+            if let Some(mmap) = mmap_dst.as_mut() {
+                if let Ok(true) = is_mmap_support(&src) {
+                    mmap_injected = true;
+                    injected_bytes = inject_mmap(&src, mmap, offset as usize)? as u64;
+                }
+            }
+
+            if !mmap_injected {
+                injected_bytes = inject_io(&src, &dst, offset)?;
+            }
+
+            let src_size = src.metadata()?.len();
+
+            if injected_bytes != src_size {
+                let _ = evx.send(InjectEvent::SizeMismatch {
+                    injected: injected_bytes,
+                    expected: src_size,
+                });
+                failed += 1;
+            } else if remove_injected {
+                remove_file(path)?;
+            }
+
+            let _ = evx.send(InjectEvent::Injected {
+                offset,
+                size: injected_bytes,
+            });
+        }
+
+        Ok::<_, Error>((failed, mmap_dst))
+    });
+
+    // Both threads hold a sender, the loop ends when they are done
+    for event in evrx {
+        on_event(event);
+    }
+
+    let walked = join(walker, "walker");
+    let (failed, mmap_dst) = join(injector, "injector")?;
+    let skipped = walked?;
+
+    if let Some(mmap) = &mmap_dst {
+        mmap.flush()?;
+    }
+
+    if skipped > 0 || failed > 0 {
+        return Err(Error::Validation(format!(
+            "{} stream(s) skipped, {} stream(s) failed to inject",
+            skipped, failed
+        )));
+    }
+
+    if remove_injected {
+        remove_file(Manifest::path(input_dir))?;
+    }
+
+    // The journal of an erase is done with once every range is back
+    let journal_path = Journal::path(file_path);
+
+    if journal_path.exists() {
+        let (journal, _) = Journal::read(file_path)?;
+        let dst = File::open(file_path)?;
+
+        let is_restored = journal.ranges.iter().all(|range| {
+            matches!(hash_range(&dst, range.offset, range.size), Ok(hash) if hash == range.sha256)
+        });
+
+        if is_restored {
+            remove_file(journal_path)?;
+        } else {
+            on_event(InjectEvent::JournalKept(journal_path));
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,209 @@
+use range_set_blaze::RangeSetBlaze;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::manifest::{hash_bytes, ManifestEntry, SourceInfo};
+
+const MAGIC: &[u8; 8] = b"MPSDJRNL";
+
+/// A range of the input file that is about to be erased.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalRange {
+    pub offset: u64,
+    pub size: u64,
+    /// SHA-256 of the original bytes (lowercase hex)
+    pub sha256: String,
+    /// Offset of the original bytes in the journal data section
+    /// (only set when the streams were not extracted)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_offset: Option<u64>,
+}
+
+/// Undo journal written next to the input file before erasing.
+///
+/// File layout: magic, header length (u64 LE), JSON header, then the
+/// data section with the original bytes of the ranges (if any).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    pub source: SourceInfo,
+    /// Folder with the extracted streams (extract mode only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streams_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub streams: Vec<ManifestEntry>,
+    pub ranges: Vec<JournalRange>,
+}
+
+impl Journal {
+    /// Builds the journal for the given sectors. When `streams_dir` is
+    /// `None` the original bytes are kept in the journal itself.
+    pub fn new(
+        source: SourceInfo,
+        sectors: &RangeSetBlaze<usize>,
+        buffer: &[u8],
+        streams_dir: Option<PathBuf>,
+        streams: Vec<ManifestEntry>,
+    ) -> Self {
+        let keep_data = streams_dir.is_none();
+        let mut data_offset = 0;
+        let mut ranges = Vec::new();
+
+        for r in sectors.ranges() {
+            let bytes = &buffer[*r.start()..=*r.end()];
+
+            ranges.push(JournalRange {
+                offset: *r.start() as u64,
+                size: bytes.len() as u64,
+                sha256: hash_bytes(bytes),
+                data_offset: keep_data.then_some(data_offset),
+            });
+
+            if keep_data {
+                data_offset += bytes.len() as u64;
+            }
+        }
+
+        Journal {
+            source,
+            streams_dir,
+            streams,
+            ranges,
+        }
+    }
+
+    pub fn path(file_path: &Path) -> PathBuf {
+        let mut path = file_path.as_os_str().to_owned();
+        path.push(".mpsd-journal");
+        PathBuf::from(path)
+    }
+
+    /// Writes the journal (and the original bytes of the ranges which
+    /// have `data_offset` set) and syncs it to disk.
+    pub fn write(&self, file_path: &Path, buffer: &[u8]) -> Result<()> {
+        let path = Self::path(file_path);
+
+        self.write_to(&path, buffer)
+            .map_err(|err| Error::Journal(path, err))
+    }
+
+    fn write_to(&self, path: &Path, buffer: &[u8]) -> std::io::Result<()> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+
+        let header = serde_json::to_vec(self)?;
+        let mut writer = BufWriter::new(&file);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(header.len() as u64).to_le_bytes())?;
+        writer.write_all(&header)?;
+
+        for r in self.ranges.iter().filter(|r| r.data_offset.is_some()) {
+            let start = r.offset as usize;
+            writer.write_all(&buffer[start..start + r.size as usize])?;
+        }
+
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Reads the journal header. Returns the journal and the position
+    /// of the data section in the journal file.
+    pub fn read(file_path: &Path) -> Result<(Self, u64)> {
+        let path = Self::path(file_path);
+
+        Self::read_from(&path).map_err(|err| Error::Journal(path, err))
+    }
+
+    fn read_from(path: &Path) -> std::io::Result<(Self, u64)> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        let mut header_len = [0; 8];
+
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Not an mpsd journal",
+            ));
+        }
+
+        reader.read_exact(&mut header_len)?;
+
+        let header_len = u64::from_le_bytes(header_len);
+        let mut header = vec![0; header_len as usize];
+        reader.read_exact(&mut header)?;
+
+        let journal = serde_json::from_slice(&header)?;
+        let data_start = (MAGIC.len() + size_of::<u64>()) as u64 + header_len;
+
+        Ok((journal, data_start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::tests::temp_dir;
+    use std::fs;
+
+    fn sectors() -> RangeSetBlaze<usize> {
+        RangeSetBlaze::from_iter([2..=4, 7..=8])
+    }
+
+    #[test]
+    fn ranges_keep_the_data_without_streams_dir() {
+        let buffer = b"0123456789";
+        let source = SourceInfo::from_stream(Path::new("in.bin"), 10);
+
+        let journal = Journal::new(source.clone(), &sectors(), buffer, None, Vec::new());
+        let ranges: Vec<_> = journal
+            .ranges
+            .iter()
+            .map(|r| (r.offset, r.size, r.data_offset))
+            .collect();
+
+        assert_eq!(ranges, [(2, 3, Some(0)), (7, 2, Some(3))]);
+        assert_eq!(journal.ranges[0].sha256, hash_bytes(b"234"));
+
+        let journal = Journal::new(source, &sectors(), buffer, Some("out".into()), Vec::new());
+        assert!(journal.ranges.iter().all(|r| r.data_offset.is_none()));
+    }
+
+    #[test]
+    fn write_and_read() {
+        let dir = temp_dir("journal");
+        let file_path = dir.join("in.bin");
+        let buffer = b"0123456789";
+        let source = SourceInfo::from_stream(&file_path, 10);
+
+        let journal = Journal::new(source, &sectors(), buffer, None, Vec::new());
+        journal.write(&file_path, buffer).unwrap();
+
+        let (read, data_start) = Journal::read(&file_path).unwrap();
+        assert_eq!(read.source.size, 10);
+        assert_eq!(read.ranges.len(), 2);
+        assert_eq!(read.ranges[1].sha256, journal.ranges[1].sha256);
+
+        let bytes = fs::read(Journal::path(&file_path)).unwrap();
+        assert_eq!(&bytes[..8], MAGIC);
+        assert_eq!(&bytes[data_start as usize..], b"23478");
+
+        // An existing journal is never overwritten
+        assert!(matches!(
+            journal.write(&file_path, buffer),
+            Err(Error::Journal(..))
+        ));
+
+        fs::write(Journal::path(&file_path), b"not a journal").unwrap();
+        assert!(matches!(Journal::read(&file_path), Err(Error::Journal(..))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+//! Multi-Pattern Streams Detector
+//!
+//! Detects streams of various formats embedded in binary data. Use
+//! [`Scanner`] to search a buffer or a file:
+//!
+//! ```no_run
+//! use mpsd::{Scanner, StreamType};
+//!
+//! let scanner = Scanner::builder()
+//!     .stream_types(&[StreamType::Ogg, StreamType::RiffWave])
+//!     .build()
+//!     .unwrap();
+//!
+//! let buffer = std::fs::read("input.bin").unwrap();
+//!
+//! for m in scanner.scan(&buffer) {
+//!     println!("{:?} @ {} ({} bytes)", m.stream_type, m.offset, m.size);
+//! }
+//! ```
+//!
+//! [`Scanner::scan_with`] spreads the detection over several threads
+//! (see [`ScannerBuilder::threads`]) and reports the matches in the same
+//! order. [`Scanner::scan_reader`] reads inputs that cannot be
+//! memory-mapped (stdin, pipes) through a sliding window.
+//!
+//! The commands of the `mpsd` binary work on files: [`extract_file`]
+//! scans a file and writes the found streams with a manifest, [`inject`]
+//! writes them back, [`verify`] checks them against the file and
+//! [`unerase`] restores the sectors erased during extraction.
+
+pub mod detector;
+pub mod eraser;
+pub mod error;
+pub mod extractor;
+pub mod injector;
+pub mod journal;
+pub mod manifest;
+pub mod scanner;
+pub mod verifier;
+
+pub use detector::{DetectOptions, Detector, MetaValue, Metadata, StreamMatch, StreamType};
+pub use eraser::{unerase, UneraseEvent, UneraseReport};
+pub use error::{Error, Result};
+pub use extractor::{extract_file, ExtractOptions, Summary, TypeStats};
+pub use injector::{inject, InjectEvent};
+pub use scanner::{DetectorStats, Matches, ScanStats, Scanner, ScannerBuilder};
+pub use verifier::{verify, VerifyEvent, VerifyReport};
@@ -1,449 +1,294 @@
-use aho_corasick::AhoCorasick;
-use bytes::{Buf, Bytes};
 use colored::Colorize;
-use glob::glob;
-use injector::is_mmap_support;
-use memmap2::{Mmap, MmapMut};
-use range_set_blaze::RangeSetBlaze;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, remove_file, OpenOptions};
-use std::iter::Iterator;
-use std::path::PathBuf;
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
-use std::time::{Duration, Instant};
-
-use detector::{
-    AacDetector, BitmapDetector, DetectOptions, Detector, Mp3Detector, OggDetector,
-    RiffWaveDetector, StreamMatch, StreamType,
+use mpsd::{
+    extract_file, inject, unerase, verify, DetectOptions, Error, ExtractOptions, InjectEvent,
+    Result, Scanner, StreamType, UneraseEvent, VerifyEvent, VerifyReport,
 };
+use std::path::Path;
+use std::process::ExitCode;
 
 mod cli;
-mod detector;
-mod eraser;
-mod extractor;
-mod injector;
-
-struct Args {
-    silent: bool,
-    is_extract: bool,
-    injected_rm: bool,
-    erase_sectors: bool,
-    file_path: Option<PathBuf>,
-    input_dir: Option<PathBuf>,
-    output_dir: Option<PathBuf>,
-    detect_options: DetectOptions,
-    patterns: HashMap<Bytes, Vec<StreamType>>,
+mod output;
+
+use output::Reporter;
+
+/// Exit codes of the binary (2 is used by clap for invalid arguments)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    Success = 0,
+    NoStreamsFound = 1,
+    Validation = 3,
+    Io = 4,
+    DiskFull = 5,
+    Mmap = 6,
+    Manifest = 7,
+    Pattern = 8,
 }
 
-struct State {
-    silent: bool,
-    is_extract: bool,
-    total_streams_size: usize,
-    total_streams_count: usize,
-    processed_sectors: RangeSetBlaze<usize>,
-}
-
-struct Summary {
-    process_time: Duration,
-    processed_bytes: usize,
-    total_streams_size: usize,
-    total_streams_count: usize,
-}
-
-fn handle_offset(
-    buffer: &Mmap,
-    offset: usize,
-    stream_types: &Vec<StreamType>,
-    extractor: &mpsc::Sender<(usize, usize, String)>,
-    detect_options: &DetectOptions,
-    state: &mut State,
-) {
-    for x in stream_types {
-        if state.processed_sectors.contains(offset) {
-            return;
-        }
-
-        let detector: Box<dyn Detector> = match x {
-            StreamType::Aac => Box::new(AacDetector),
-            StreamType::Ogg => Box::new(OggDetector),
-            StreamType::Mp3 => Box::new(Mp3Detector),
-            StreamType::Bitmap => Box::new(BitmapDetector),
-            StreamType::RiffWave => Box::new(RiffWaveDetector),
-        };
-
-        if let Some(StreamMatch { offset, size, ext }) =
-            detector.detect(buffer, offset, detect_options)
-        {
-            let sector = offset..=(offset + size);
-            (*state).total_streams_count += 1;
-            (*state).total_streams_size += size;
-            (*state).processed_sectors.ranges_insert(sector);
-
-            if state.is_extract {
-                extractor
-                    .send((offset, size, ext.to_string()))
-                    .expect("could not synchronize threads");
+impl From<&Error> for Exit {
+    fn from(err: &Error) -> Self {
+        if let Some(err) = err.io_error() {
+            if err.kind() == std::io::ErrorKind::StorageFull {
+                return Exit::DiskFull;
             }
+        }
 
-            if !state.silent {
-                println!("--> Found {:?} stream @ {} ({} bytes)", x, offset, size);
-            }
+        match err {
+            Error::Io(_) => Exit::Io,
+            Error::Mmap(_) => Exit::Mmap,
+            Error::Pattern(_) => Exit::Pattern,
+            Error::Manifest(..) | Error::Journal(..) => Exit::Manifest,
+            Error::Validation(_) => Exit::Validation,
         }
     }
 }
 
-fn run(args: Args) -> Summary {
-    let file_path = args.file_path.expect("file path is not set");
-
-    let file = OpenOptions::new()
-        .write(args.is_extract && args.erase_sectors)
-        .read(true)
-        .open(file_path)
-        .expect("failed to open the file");
-
-    let mmap = Arc::new(unsafe { Mmap::map(&file).expect("failed to mmap the file") });
-
-    let mut output_dir: PathBuf = PathBuf::new();
-
-    if args.is_extract {
-        output_dir = args.output_dir.expect("output directory is not set");
+/// Scans (and extracts) the input, reporting the found streams and the
+/// summary. Returns the number of found streams.
+fn scan(
+    file_path: &str,
+    scanner: &Scanner,
+    options: &ExtractOptions,
+    reporter: &mut Reporter,
+) -> Result<usize> {
+    let summary = extract_file(Path::new(file_path), scanner, options, |m| {
+        reporter.stream(m)
+    })?;
+
+    if summary.truncated_streams > 0 {
+        eprintln!(
+            "{} stream(s) reached the end of the read window and may be cut, use a larger --window-size",
+            summary.truncated_streams
+        );
     }
 
-    let output_dir_cloned = output_dir.clone();
-
-    if args.is_extract {
-        create_dir_all(output_dir).expect("could not create directory for extracting files");
+    match summary.erased_bytes {
+        Some(erased) if erased != summary.total_streams_size => eprintln!(
+            "Total erased bytes ({}) does not match the total streams size ({})",
+            erased, summary.total_streams_size
+        ),
+        _ => {}
     }
 
-    let start_time = Instant::now();
-    let (ssx, drx) = mpsc::channel();
-
-    let (byte1_patterns, patterns): (Vec<Bytes>, Vec<Bytes>) = args
-        .patterns
-        .keys()
-        .cloned()
-        .into_iter()
-        .partition(|x| x.len() == 1);
-
-    let ac = AhoCorasick::new(&patterns).expect("could not initiate AhoCorasick");
-    let ssx_cloned = ssx.clone();
-    let mmap_cloned = Arc::clone(&mmap);
-    let patterns_cloned = args.patterns.clone();
-
-    let scanner = thread::spawn(move || {
-        if ac.patterns_len() > 0 {
-            for c in ac.find_iter(&*mmap_cloned) {
-                let pattern = &patterns[c.pattern()];
-
-                if let Some(stream_types) = patterns_cloned.get(pattern) {
-                    ssx_cloned
-                        .send((c.start(), stream_types.clone()))
-                        .expect("could not synchronize threads");
-                }
-            }
-        }
-    });
-
-    let byte1_ssx_cloned = ssx.clone();
-    let byte1_mmap_cloned = Arc::clone(&mmap);
-    let patterns_cloned = args.patterns.clone();
-
-    let byte1_scanner = thread::spawn(move || {
-        for pattern in byte1_patterns {
-            let it = memchr::memchr_iter(pattern.clone().get_u8(), &byte1_mmap_cloned);
-
-            for c in it {
-                byte1_ssx_cloned
-                    .send((c, patterns_cloned.get(&pattern).unwrap().clone()))
-                    .expect("could not synchronize threads");
-            }
-        }
-    });
+    reporter.summary(&summary);
 
-    drop(ssx);
-
-    let mmap_cloned = Arc::clone(&mmap);
-
-    let state = Arc::new(Mutex::new(State {
-        silent: args.silent,
-        total_streams_size: 0,
-        total_streams_count: 0,
-        is_extract: args.is_extract,
-        processed_sectors: RangeSetBlaze::new(),
-    }));
-
-    let state_cloned = Arc::clone(&state);
-    let (esx, erx) = mpsc::channel();
-    let esx_cloned = esx.clone();
-
-    let detector = thread::spawn(move || {
-        let mut state = state_cloned.lock().expect("could not lock the state");
-
-        for (offset, stream_types) in drx {
-            handle_offset(
-                &mmap_cloned,
-                offset,
-                &stream_types,
-                &esx_cloned,
-                &args.detect_options,
-                &mut state,
-            );
-        }
-    });
-
-    drop(esx);
-
-    let mmap_cloned = Arc::clone(&mmap);
+    Ok(summary.total_streams_count)
+}
 
-    let extractor = thread::spawn(move || {
-        for (offset, size, ext) in erx {
-            extractor::extract(&mmap_cloned, offset, size, &ext, &output_dir_cloned)
-                .expect("failed to extract the stream");
+fn print_inject_event(event: InjectEvent, silent: bool) {
+    match event {
+        InjectEvent::Modified { mtime, expected } => eprintln!(
+            "File was modified after extraction (mtime {} != {})",
+            mtime, expected
+        ),
+        InjectEvent::Missing(file) => eprintln!("Stream file {} is missing, skipping", file),
+        InjectEvent::HashMismatch(path) => {
+            eprintln!("Stream file {} hash mismatch, skipping", path.display())
         }
-    });
-
-    scanner.join().expect("scanner thread panicked");
-    detector.join().expect("detector thread panicked");
-    extractor.join().expect("extractor thread panicked");
-    byte1_scanner.join().expect("byte1 scanner thread panicked");
-
-    let state = state.lock().expect("could not lock the state");
-
-    if args.is_extract && args.erase_sectors {
-        let total_erased_bytes = eraser::erase_sectors(&file, &state.processed_sectors)
-            .expect("failed to erase sectors");
-
-        if total_erased_bytes != state.total_streams_size {
-            eprintln!(
-                "Total erased bytes ({}) does not match the total streams size ({})",
-                total_erased_bytes, state.total_streams_size
-            );
+        InjectEvent::SizeMismatch { injected, expected } => eprintln!(
+            "Injected bytes ({}) does not match the source file size ({})",
+            injected, expected
+        ),
+        InjectEvent::Injected { offset, size } => {
+            if !silent {
+                println!("--> Injected {} bytes @ {}", size, offset);
+            }
         }
-    }
-
-    Summary {
-        processed_bytes: mmap.len(),
-        process_time: start_time.elapsed(),
-        total_streams_size: state.total_streams_size,
-        total_streams_count: state.total_streams_count,
+        InjectEvent::JournalKept(path) => eprintln!(
+            "Journal {} is kept, not every erased range was restored",
+            path.display()
+        ),
     }
 }
 
-fn run_injector(args: Args) {
-    let file_path = args.file_path.expect("file path is not set");
-    let input_dir = args.input_dir.expect("input dir is not set");
-    let mut input_path = input_dir;
-    let input_path_str = input_path.to_str().expect("failed to get input path");
-
-    if !input_path_str.contains("*") {
-        input_path = input_path.join("*.*");
-    }
-
-    let dst = OpenOptions::new()
-        .write(true)
-        .read(true)
-        .open(file_path)
-        .expect("failed to open the file");
-
-    let input_path_str = input_path
-        .to_str()
-        .expect("failed to get input path")
-        .to_owned();
-
-    let (sx, rx) = mpsc::channel();
-    let sx_cloned = sx.clone();
-
-    let mut mmap_dst = Arc::new(Mutex::new(None));
-
-    if let Ok(true) = is_mmap_support(&dst) {
-        mmap_dst = Arc::new(Mutex::new(Some(unsafe {
-            MmapMut::map_mut(&dst).expect("failed to mmap the file")
-        })));
-    }
-
-    let walker = thread::spawn(move || {
-        for entry in glob(&input_path_str).unwrap() {
-            if let Ok(path) = entry {
-                let file_name = path.file_stem().unwrap().to_str().unwrap();
-                let offset = file_name.parse::<i128>().unwrap_or(-1);
-
-                if offset < 0 {
-                    continue;
-                }
-
-                let uoffset = u64::try_from(offset).unwrap();
-
-                sx_cloned
-                    .send((path, uoffset))
-                    .expect("could not synchronize threads");
-            }
+fn print_verify_event(event: VerifyEvent, silent: bool) {
+    match event {
+        VerifyEvent::SizeMismatch { size, expected } => eprintln!(
+            "File size ({}) does not match the size recorded in the manifest ({})",
+            size, expected
+        ),
+        VerifyEvent::Missing(entry) => println!("--> Missing {} @ {}", entry.file, entry.offset),
+        VerifyEvent::Modified(entry, path) => {
+            println!("--> Modified {} @ {}", path.display(), entry.offset)
         }
-    });
-
-    drop(sx);
-
-    let mmap_dst_cloned = Arc::clone(&mmap_dst);
-
-    let injector = thread::spawn(move || {
-        let mut mmap_lock = mmap_dst_cloned.lock().expect("failed to acquire lock");
-
-        for (path, offset) in rx {
-            let src = OpenOptions::new()
-                .read(true)
-                .open(&path)
-                .expect("failed to open the file");
-
-            let mut injected_bytes: u64 = 0;
-            let mut mmap_injected = false;
-
-            if let Some(ref mut mmap) = mmap_lock.as_mut() {
-                if let Ok(true) = is_mmap_support(&src) {
-                    mmap_injected = true;
-                    injected_bytes = injector::inject_mmap(&src, mmap, offset as usize)
-                        .expect("failed to inject the stream (mmap)")
-                        as u64;
-                }
-            }
-
-            if !mmap_injected {
-                injected_bytes = injector::inject_io(&src, &dst, offset)
-                    .expect("failed to inject the stream (io)");
-            }
-
-            let src_size = src.metadata().unwrap().len();
-
-            if injected_bytes != src_size {
-                eprintln!(
-                    "Injected bytes ({}) does not match the source file size ({})",
-                    injected_bytes, src_size
+        VerifyEvent::Matched(entry) => {
+            if !silent {
+                println!(
+                    "--> OK {} @ {} ({} bytes)",
+                    entry.file, entry.offset, entry.size
                 );
-            } else if args.injected_rm {
-                remove_file(path).expect("failed to remove a file");
-            }
-
-            if !args.silent {
-                println!("--> Injected {} bytes @ {}", injected_bytes, offset);
             }
         }
-    });
-
-    walker.join().expect("walker thread panicked");
-    injector.join().expect("injector thread panicked");
-
-    let mut mmap_lock = mmap_dst.lock().expect("failed to acquire lock");
-
-    if let Some(mmap) = mmap_lock.as_mut() {
-        mmap.flush().expect("failed to save file on disk");
+        VerifyEvent::Mismatched(entry) => println!(
+            "--> Mismatch {} @ {} ({} bytes)",
+            entry.file, entry.offset, entry.size
+        ),
+        VerifyEvent::Extra(path) => println!("--> Extra {}", path.display()),
     }
 }
 
-fn humanize_size(bytes: usize) -> String {
-    const UNITS: [&str; 7] = ["B", "KB", "MB", "GB", "TB", "PB", "EB"];
-
-    let exp = (bytes as f64).log(1024.0).floor() as usize;
-    let size_in_units = bytes as f64 / 1024_f64.powi(exp as i32);
-
-    format!("{:.2} {}", size_in_units, UNITS[exp])
+fn print_unerase_event(event: UneraseEvent, silent: bool) {
+    match event {
+        UneraseEvent::Missing(entry) => eprintln!("Stream file {} is missing", entry.file),
+        UneraseEvent::Restored(range) => {
+            if !silent {
+                println!("--> Restored {} bytes @ {}", range.size, range.offset);
+            }
+        }
+        UneraseEvent::Failed(range) => eprintln!(
+            "--> Could not restore {} bytes @ {}",
+            range.size, range.offset
+        ),
+    }
 }
 
-fn print_summary(summary: &Summary) {
-    let elapsed_seconds = summary.process_time.as_secs_f64();
-    let processed_bytes = summary.processed_bytes as f64;
-    let speed_mbps = (processed_bytes / (1024.0 * 1024.0)) / elapsed_seconds;
-
+fn print_verify_report(report: &VerifyReport) {
     println!("\n{}", "Summary:\n".bold().underline());
-    println!("-> Processed: {}", humanize_size(summary.processed_bytes));
-    println!("-> Process time: {:?}", summary.process_time);
-    println!("-> Speed: {:.2} MB/s", speed_mbps);
-    println!("-> Found streams: {}", summary.total_streams_count);
-    println!(
-        "-> Size of found streams: {} ({} bytes)",
-        humanize_size(summary.total_streams_size),
-        summary.total_streams_size
-    );
+    println!("-> Matched streams: {}", report.ok);
+    println!("-> Mismatched streams: {}", report.mismatched);
+    println!("-> Modified stream files: {}", report.modified);
+    println!("-> Missing stream files: {}", report.missing);
+    println!("-> Extra files: {}", report.extra);
+
+    match report.file_restored {
+        Some(true) => println!("-> Original file: {}", "restored".green()),
+        Some(false) => println!("-> Original file: {}", "differs".red()),
+        None => {}
+    }
 }
 
-fn main() {
-    let cli_args: cli::Cli = cli::parse();
-
+fn run_command(cli_args: cli::Cli) -> Result<Exit> {
     let detect_options = DetectOptions {
         mpeg_min_frames: cli_args.mpeg_min_frames,
         mpeg_max_frames: cli_args.mpeg_max_frames,
+        bmp_strict: cli_args.bmp_strict,
     };
 
-    let mut patterns: HashMap<Bytes, Vec<StreamType>> = HashMap::from([
-        (Bytes::from("OggS"), vec![StreamType::Ogg]),
-        (Bytes::from("BM"), vec![StreamType::Bitmap]),
-        (Bytes::from("RIFF"), vec![StreamType::RiffWave]),
-        (
-            Bytes::from(&b"\xFF"[..]),
-            vec![StreamType::Aac, StreamType::Mp3],
-        ),
-    ]);
-
-    patterns.retain(|_, v| match v.as_slice() {
-        [StreamType::Ogg] => cli_args.detect_ogg != 0,
-        [StreamType::Bitmap] => cli_args.detect_bmp != 0,
-        [StreamType::RiffWave] => cli_args.detect_wav != 0,
-        [StreamType::Aac, StreamType::Mp3] => {
-            if cli_args.detect_aac != 0 && cli_args.detect_mp3 != 0 {
-                return true;
-            }
-
-            if cli_args.detect_aac != 0 {
-                *v = vec![StreamType::Aac];
-                return true;
-            }
-
-            if cli_args.detect_mp3 != 0 {
-                *v = vec![StreamType::Mp3];
-                return true;
-            }
-
-            return false;
-        }
-        _ => false,
-    });
-
-    let mut args = Args {
-        patterns,
-        detect_options,
-        silent: cli_args.silent,
-        injected_rm: cli_args.injected_rm,
+    let stream_types: Vec<StreamType> = [
+        (StreamType::RiffWave, cli_args.detect_wav),
+        (StreamType::Rf64, cli_args.detect_rf64),
+        (StreamType::Wave64, cli_args.detect_w64),
+        (StreamType::Rifx, cli_args.detect_rifx),
+        (StreamType::Aiff, cli_args.detect_aiff),
+        (StreamType::Riff, cli_args.detect_riff),
+        (StreamType::Bitmap, cli_args.detect_bmp),
+        (StreamType::Ogg, cli_args.detect_ogg),
+        (StreamType::Png, cli_args.detect_png),
+        (StreamType::Jpeg, cli_args.detect_jpeg),
+        (StreamType::Flac, cli_args.detect_flac),
+        (StreamType::IsoBmff, cli_args.detect_mp4),
+        (StreamType::Aac, cli_args.detect_aac),
+        (StreamType::Mp3, cli_args.detect_mp3),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled != 0)
+    .map(|(stream_type, _)| stream_type)
+    .collect();
+
+    let scanner = Scanner::builder()
+        .stream_types(&stream_types)
+        .detect_options(detect_options)
+        .threads(cli_args.threads)
+        .chunk_size(cli_args.chunk_size as usize * 1024 * 1024)
+        .build()?;
+
+    let silent = cli_args.silent;
+
+    let options = |output_dir: Option<String>| ExtractOptions {
+        output_dir: output_dir.map(Into::into),
         erase_sectors: cli_args.erase_sectors,
-        input_dir: None,
-        output_dir: None,
-        file_path: None,
-        is_extract: false,
+        window_size: cli_args.window_size as usize * 1024 * 1024,
     };
 
     match cli_args.command {
-        cli::Commands::Scan { file_path } => {
-            println!("-> Scanning...");
-            args.file_path = Some(PathBuf::from(file_path));
-            let summary: Summary = run(args);
-            print_summary(&summary);
+        cli::Commands::Scan { file_path, format } => {
+            let mut reporter = Reporter::new(format, silent);
+
+            if reporter.is_text() {
+                println!("-> Scanning...");
+            }
+
+            if scan(&file_path, &scanner, &options(None), &mut reporter)? == 0 {
+                return Ok(Exit::NoStreamsFound);
+            }
         }
         cli::Commands::Extract {
             file_path,
             output_dir,
+            format,
         } => {
-            println!("-> Scanning and extracting...");
-            args.is_extract = true;
-            args.file_path = Some(PathBuf::from(file_path));
-            args.output_dir = Some(PathBuf::from(output_dir));
-            let summary: Summary = run(args);
-            print_summary(&summary);
+            let mut reporter = Reporter::new(format, silent);
+
+            if reporter.is_text() {
+                println!("-> Scanning and extracting...");
+            }
+
+            if scan(
+                &file_path,
+                &scanner,
+                &options(Some(output_dir)),
+                &mut reporter,
+            )? == 0
+            {
+                return Ok(Exit::NoStreamsFound);
+            }
         }
         cli::Commands::Inject {
             file_path,
             input_dir,
         } => {
             println!("-> Injecting...");
-            args.file_path = Some(PathBuf::from(file_path));
-            args.input_dir = Some(PathBuf::from(input_dir));
-            run_injector(args);
+            inject(
+                Path::new(&file_path),
+                Path::new(&input_dir),
+                cli_args.injected_rm,
+                |event| print_inject_event(event, silent),
+            )?;
+        }
+        cli::Commands::Verify {
+            file_path,
+            input_dir,
+        } => {
+            println!("-> Verifying...");
+            let report = verify(Path::new(&file_path), Path::new(&input_dir), |event| {
+                print_verify_event(event, silent)
+            })?;
+            print_verify_report(&report);
+
+            if !report.is_ok() {
+                return Ok(Exit::Validation);
+            }
+        }
+        cli::Commands::Unerase {
+            file_path,
+            input_dir,
+        } => {
+            println!("-> Restoring erased sectors...");
+            let report = unerase(
+                Path::new(&file_path),
+                input_dir.as_deref().map(Path::new),
+                |event| print_unerase_event(event, silent),
+            )?;
+
+            println!("\n{}", "Summary:\n".bold().underline());
+            println!("-> Restored ranges: {}", report.restored);
+            println!("-> Failed ranges: {}", report.failed);
+
+            if report.failed > 0 {
+                return Ok(Exit::Validation);
+            }
         }
     }
+
+    Ok(Exit::Success)
+}
+
+fn main() -> ExitCode {
+    let cli_args: cli::Cli = cli::parse();
+
+    let exit = run_command(cli_args).unwrap_or_else(|err| {
+        eprintln!("{} {}", "Error:".red().bold(), err);
+        Exit::from(&err)
+    });
+
+    ExitCode::from(exit as u8)
 }
@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::detector::StreamType;
+use crate::error::{Error, Result};
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Describes the file the streams were extracted from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceInfo {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time (seconds since UNIX epoch) after extraction
+    /// (and erasing, when enabled) has finished
+    pub mtime: u64,
+    /// SHA-256 of the whole file before erasing (only set when sectors
+    /// were erased)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// File name relative to the manifest directory
+    pub file: String,
+    pub offset: u64,
+    pub size: u64,
+    pub stream_type: StreamType,
+    pub ext: String,
+    /// SHA-256 of the stream bytes (lowercase hex)
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub source: SourceInfo,
+    pub streams: Vec<ManifestEntry>,
+}
+
+impl SourceInfo {
+    pub fn from_file(path: &Path, file: &File) -> std::io::Result<Self> {
+        let metadata = file.metadata()?;
+
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Ok(SourceInfo {
+            path: fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+            size: metadata.len(),
+            mtime,
+            sha256: None,
+        })
+    }
+
+    /// Source info of an input read as a stream (stdin, a pipe).
+    pub fn from_stream(path: &Path, size: u64) -> Self {
+        SourceInfo {
+            path: path.to_path_buf(),
+            size,
+            mtime: 0,
+            sha256: None,
+        }
+    }
+}
+
+impl ManifestEntry {
+    /// Resolves the path of the extracted stream. Falls back to looking
+    /// for a file with the same size and hash when the recorded file
+    /// name no longer exists (e.g. it was renamed).
+    pub fn resolve(&self, dir: &Path) -> std::io::Result<Option<PathBuf>> {
+        let path = dir.join(&self.file);
+
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+
+            if !path.is_file() || path.file_name() == Some(MANIFEST_FILE_NAME.as_ref()) {
+                continue;
+            }
+
+            if fs::metadata(&path)?.len() != self.size {
+                continue;
+            }
+
+            if hash_file(&File::open(&path)?)? == self.sha256 {
+                return Ok(Some(path));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Manifest {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join(MANIFEST_FILE_NAME)
+    }
+
+    pub fn read(dir: &Path) -> Result<Self> {
+        let path = Self::path(dir);
+
+        File::open(&path)
+            .and_then(|file| Ok(serde_json::from_reader(BufReader::new(file))?))
+            .map_err(|err| Error::Manifest(path, err))
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let path = Self::path(dir);
+
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                serde_json::to_writer_pretty(&mut writer, self)?;
+                writer.flush()
+            })
+            .map_err(|err| Error::Manifest(path, err))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+pub fn hash_file(file: &File) -> std::io::Result<String> {
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(0))?;
+
+    let (hash, _) = hash_reader(reader)?;
+
+    Ok(hash)
+}
+
+/// Hashes `size` bytes of the file starting at `offset`. Fails with
+/// `UnexpectedEof` when the range is out of the file bounds.
+pub fn hash_range(file: &File, offset: u64, size: u64) -> std::io::Result<String> {
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let (hash, bytes_read) = hash_reader(reader.take(size))?;
+
+    if bytes_read != size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("Range {}+{} exceeds the file size", offset, size),
+        ));
+    }
+
+    Ok(hash)
+}
+
+fn hash_reader(mut reader: impl Read) -> std::io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 128 * 1024];
+    let mut bytes_read = 0;
+
+    loop {
+        let n = reader.read(&mut buffer)?;
+
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..n]);
+        bytes_read += n as u64;
+    }
+
+    Ok((to_hex(&hasher.finalize()), bytes_read))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Creates an empty directory for a test under the system temp dir.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mpsd-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(file: &str, bytes: &[u8]) -> ManifestEntry {
+        ManifestEntry {
+            file: file.to_string(),
+            offset: 1000,
+            size: bytes.len() as u64,
+            stream_type: StreamType::Png,
+            ext: "png".to_string(),
+            sha256: hash_bytes(bytes),
+        }
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let dir = temp_dir("manifest");
+        let manifest = Manifest {
+            source: SourceInfo::from_stream(Path::new("input.bin"), 5000),
+            streams: vec![entry("1000.png", b"stream"), entry("3000.png", b"other")],
+        };
+
+        manifest.write(&dir).unwrap();
+        let read = Manifest::read(&dir).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            serde_json::to_value(&manifest).unwrap()
+        );
+        assert_eq!(read.streams[1].file, "3000.png");
+        assert_eq!(read.source.sha256, None);
+
+        fs::remove_file(Manifest::path(&dir)).unwrap();
+        assert!(matches!(Manifest::read(&dir), Err(Error::Manifest(..))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_falls_back_to_the_hash() {
+        let dir = temp_dir("resolve");
+        let entry = entry("1000.png", b"stream");
+
+        fs::write(dir.join("1000.png"), b"stream").unwrap();
+        assert_eq!(entry.resolve(&dir).unwrap(), Some(dir.join("1000.png")));
+
+        // Renamed, next to a file of the same size with other bytes
+        fs::rename(dir.join("1000.png"), dir.join("renamed.png")).unwrap();
+        fs::write(dir.join("a.png"), b"STREAM").unwrap();
+        assert_eq!(entry.resolve(&dir).unwrap(), Some(dir.join("renamed.png")));
+
+        fs::remove_file(dir.join("renamed.png")).unwrap();
+        assert_eq!(entry.resolve(&dir).unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_range_checks_the_bounds() {
+        let dir = temp_dir("hash-range");
+        let path = dir.join("in.bin");
+        fs::write(&path, b"0123456789").unwrap();
+
+        let file = File::open(&path).unwrap();
+        assert_eq!(hash_range(&file, 2, 5).unwrap(), hash_bytes(b"23456"));
+        assert_eq!(hash_range(&file, 0, 10).unwrap(), hash_file(&file).unwrap());
+
+        let err = hash_range(&file, 8, 5).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use colored::Colorize;
+use mpsd::{Metadata, StreamMatch, StreamType, Summary, TypeStats};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::cli::Format;
+
+#[derive(Serialize)]
+struct StreamRecord<'a> {
+    offset: usize,
+    size: usize,
+    #[serde(rename = "type")]
+    stream_type: StreamType,
+    ext: &'a str,
+    metadata: &'a Metadata,
+}
+
+#[derive(Serialize)]
+struct SummaryRecord {
+    processed_bytes: usize,
+    process_time_secs: f64,
+    speed_mbps: f64,
+    total_streams_count: usize,
+    total_streams_size: usize,
+    by_type: BTreeMap<StreamType, TypeStats>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "record", rename_all = "lowercase")]
+enum Record<'a> {
+    Stream(StreamRecord<'a>),
+    Summary(SummaryRecord),
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    streams: Vec<StreamRecord<'a>>,
+    summary: SummaryRecord,
+}
+
+/// Prints the found streams and the summary in the selected format.
+pub struct Reporter {
+    format: Format,
+    silent: bool,
+    csv_header_written: bool,
+    streams: Vec<StreamMatch>,
+}
+
+impl<'a> From<&'a StreamMatch> for StreamRecord<'a> {
+    fn from(m: &'a StreamMatch) -> Self {
+        StreamRecord {
+            offset: m.offset,
+            size: m.size,
+            stream_type: m.stream_type,
+            ext: m.ext,
+            metadata: &m.metadata,
+        }
+    }
+}
+
+impl From<&Summary> for SummaryRecord {
+    fn from(summary: &Summary) -> Self {
+        SummaryRecord {
+            processed_bytes: summary.processed_bytes,
+            process_time_secs: summary.process_time.as_secs_f64(),
+            speed_mbps: speed_mbps(summary),
+            total_streams_count: summary.total_streams_count,
+            total_streams_size: summary.total_streams_size,
+            by_type: summary.by_type.clone(),
+        }
+    }
+}
+
+impl Reporter {
+    pub fn new(format: Format, silent: bool) -> Self {
+        Reporter {
+            format,
+            silent,
+            csv_header_written: false,
+            streams: Vec::new(),
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == Format::Text
+    }
+
+    pub fn stream(&mut self, m: &StreamMatch) {
+        match self.format {
+            Format::Text if self.silent => {}
+            Format::Text => println!(
+                "--> Found {:?} stream @ {} ({} bytes)",
+                m.stream_type, m.offset, m.size
+            ),
+            Format::Json => self.streams.push(m.clone()),
+            Format::Jsonl => print_json(&Record::Stream(m.into())),
+            Format::Csv => {
+                self.write_csv_header();
+                println!(
+                    "stream,{},{},{:?},{},{}",
+                    m.offset,
+                    m.size,
+                    m.stream_type,
+                    csv_escape(m.ext),
+                    csv_escape(&key_values(&m.metadata))
+                );
+            }
+        }
+    }
+
+    pub fn summary(&mut self, summary: &Summary) {
+        match self.format {
+            Format::Text => print_summary(summary),
+            Format::Json => print_json(&Document {
+                streams: self.streams.iter().map(StreamRecord::from).collect(),
+                summary: summary.into(),
+            }),
+            Format::Jsonl => print_json(&Record::Summary(summary.into())),
+            Format::Csv => {
+                self.write_csv_header();
+
+                for (stream_type, stats) in &summary.by_type {
+                    println!(
+                        "format,,{},{:?},,{}",
+                        stats.total_size,
+                        stream_type,
+                        csv_escape(&key_values(stats))
+                    );
+                }
+
+                println!(
+                    "summary,,{},,,{}",
+                    summary.total_streams_size,
+                    csv_escape(&key_values(&SummaryRecord::from(summary)))
+                );
+            }
+        }
+    }
+
+    fn write_csv_header(&mut self) {
+        if !self.csv_header_written {
+            println!("record,offset,size,type,ext,metadata");
+            self.csv_header_written = true;
+        }
+    }
+}
+
+fn print_json(value: &impl Serialize) {
+    match serde_json::to_string(value) {
+        Ok(json) => println!("{}", json),
+        Err(err) => eprintln!("Failed to serialize the record: {}", err),
+    }
+}
+
+/// Flattens a serializable map/struct to `key=value;key=value`
+/// (nested objects are skipped).
+fn key_values(value: &impl Serialize) -> String {
+    let Ok(serde_json::Value::Object(map)) = serde_json::to_value(value) else {
+        return String::new();
+    };
+
+    map.iter()
+        .filter(|(_, v)| !v.is_object())
+        .map(|(k, v)| match v {
+            serde_json::Value::String(s) => format!("{}={}", k, s),
+            v => format!("{}={}", k, v),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn speed_mbps(summary: &Summary) -> f64 {
+    let elapsed_seconds = summary.process_time.as_secs_f64();
+    let processed_bytes = summary.processed_bytes as f64;
+
+    (processed_bytes / (1024.0 * 1024.0)) / elapsed_seconds
+}
+
+fn humanize_size(bytes: usize) -> String {
+    const UNITS: [&str; 7] = ["B", "KB", "MB", "GB", "TB", "PB", "EB"];
+
+    let exp = (bytes as f64).log(1024.0).floor() as usize;
+    let size_in_units = bytes as f64 / 1024_f64.powi(exp as i32);
+
+    format!("{:.2} {}", size_in_units, UNITS[exp])
+}
+
+fn print_summary(summary: &Summary) {
+    println!("\n{}", "Summary:\n".bold().underline());
+    println!("-> Processed: {}", humanize_size(summary.processed_bytes));
+    println!("-> Process time: {:?}", summary.process_time);
+    println!("-> Speed: {:.2} MB/s", speed_mbps(summary));
+    println!("-> Found streams: {}", summary.total_streams_count);
+    println!(
+        "-> Size of found streams: {} ({} bytes)",
+        humanize_size(summary.total_streams_size),
+        summary.total_streams_size
+    );
+
+    if summary.by_type.is_empty() {
+        return;
+    }
+
+    println!("-> By format:");
+
+    for (stream_type, stats) in &summary.by_type {
+        println!(
+            "   {:?}: {} streams, {} ({:.2}%), min {} bytes, max {} bytes, accepted {}/{} candidates",
+            stream_type,
+            stats.count,
+            humanize_size(stats.total_size),
+            stats.coverage_percent,
+            stats.min_size,
+            stats.max_size,
+            stats.accepted,
+            stats.candidates
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields() {
+        let metadata = Metadata::from([("format", "pcm".into()), ("channels", 2u32.into())]);
+
+        assert_eq!(key_values(&metadata), "channels=2;format=pcm");
+        assert_eq!(csv_escape("pcm"), "pcm");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
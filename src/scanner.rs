@@ -0,0 +1,1022 @@
+use aho_corasick::{AhoCorasick, Input};
+use memmap2::Mmap;
+use range_set_blaze::RangeSetBlaze;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::iter::Peekable;
+use std::ops::{ControlFlow, Range};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::detector::{DetectOptions, StreamMatch, StreamType};
+use crate::error::{Error, Result};
+
+type Patterns = Vec<(&'static [u8], Vec<StreamType>)>;
+type Candidate<'a> = (usize, &'a [StreamType]);
+type StatsTable = [DetectorStats; StreamType::ALL.len()];
+
+/// Chunks smaller than this are not worth a thread of their own.
+const MIN_CHUNK_SIZE: usize = 1024 * 1024;
+const DEFAULT_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+const MIN_WINDOW_SIZE: usize = 1024 * 1024;
+
+/// Builder for [`Scanner`].
+#[derive(Debug, Clone)]
+pub struct ScannerBuilder {
+    stream_types: Vec<StreamType>,
+    detect_options: DetectOptions,
+    threads: usize,
+    chunk_size: usize,
+}
+
+impl Default for ScannerBuilder {
+    fn default() -> Self {
+        ScannerBuilder {
+            stream_types: StreamType::ALL.to_vec(),
+            detect_options: DetectOptions::default(),
+            threads: 1,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+impl ScannerBuilder {
+    /// Sets the stream types to detect (all types by default).
+    pub fn stream_types(mut self, stream_types: &[StreamType]) -> Self {
+        self.stream_types = stream_types.to_vec();
+        self
+    }
+
+    pub fn detect_options(mut self, detect_options: DetectOptions) -> Self {
+        self.detect_options = detect_options;
+        self
+    }
+
+    /// Sets the number of detection threads used by [`Scanner::scan_with`]
+    /// (1 by default, 0 = number of available CPUs).
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Sets the size of the chunks the buffer is split into when scanning
+    /// with several threads (16 MiB by default, at least 1 MiB).
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn build(self) -> Result<Scanner> {
+        // Patterns grouped by their position in the stream
+        let mut groups: BTreeMap<usize, Patterns> = BTreeMap::new();
+
+        let stream_types = StreamType::ALL
+            .into_iter()
+            .filter(|x| self.stream_types.contains(x));
+
+        for stream_type in stream_types {
+            let patterns = groups.entry(stream_type.signature_offset()).or_default();
+
+            for &signature in stream_type.signatures() {
+                match patterns.iter_mut().find(|(x, _)| *x == signature) {
+                    Some((_, types)) => types.push(stream_type),
+                    None => patterns.push((signature, vec![stream_type])),
+                }
+            }
+        }
+
+        let mut byte1_patterns = Patterns::new();
+        let mut pattern_sets = Vec::new();
+
+        for (lookbehind, patterns) in groups {
+            let (byte1, patterns): (Patterns, Patterns) = patterns
+                .into_iter()
+                .partition(|(x, _)| x.len() == 1 && lookbehind == 0);
+
+            byte1_patterns.extend(byte1);
+
+            if !patterns.is_empty() {
+                pattern_sets.push(PatternSet {
+                    lookbehind,
+                    ac: AhoCorasick::new(patterns.iter().map(|(x, _)| x))?,
+                    patterns,
+                });
+            }
+        }
+
+        let threads = match self.threads {
+            0 => thread::available_parallelism().map_or(1, |x| x.get()),
+            threads => threads,
+        };
+
+        Ok(Scanner {
+            pattern_sets,
+            byte1_patterns,
+            detect_options: self.detect_options,
+            threads,
+            chunk_size: self.chunk_size.max(MIN_CHUNK_SIZE),
+        })
+    }
+}
+
+/// Multi-byte patterns located `lookbehind` bytes after the stream start.
+#[derive(Debug, Clone)]
+struct PatternSet {
+    lookbehind: usize,
+    ac: AhoCorasick,
+    patterns: Patterns,
+}
+
+/// Searches a buffer for signatures of the enabled stream types and runs
+/// the detectors on every candidate offset.
+///
+/// Matches are yielded in ascending offset order. A candidate offset that
+/// falls inside an already found stream is skipped.
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    pattern_sets: Vec<PatternSet>,
+    byte1_patterns: Patterns,
+    detect_options: DetectOptions,
+    threads: usize,
+    chunk_size: usize,
+}
+
+impl Scanner {
+    pub fn builder() -> ScannerBuilder {
+        ScannerBuilder::default()
+    }
+
+    pub fn detect_options(&self) -> &DetectOptions {
+        &self.detect_options
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Scans the buffer sequentially on the calling thread.
+    pub fn scan<'a>(&'a self, buffer: &'a [u8]) -> Matches<'a> {
+        Matches {
+            state: DetectState::new(buffer, &self.detect_options),
+            candidates: self.candidates(buffer, 0..buffer.len()),
+        }
+    }
+
+    /// Scans the buffer with the configured number of threads and calls `f`
+    /// for every match in ascending offset order. Returning
+    /// `ControlFlow::Break` from `f` stops the scan.
+    ///
+    /// The buffer is split into chunks which the threads take one after
+    /// another. Each chunk is searched for signatures and scanned as if
+    /// nothing had been found before it; the chunks are then merged in
+    /// order, re-scanning the start of a chunk when a stream of the
+    /// previous one runs into it. The result is the same as with [`scan`].
+    ///
+    /// [`scan`]: Scanner::scan
+    pub fn scan_with<F>(&self, buffer: &[u8], mut f: F) -> ScanStats
+    where
+        F: FnMut(&StreamMatch) -> ControlFlow<()>,
+    {
+        let chunks = self.chunks(buffer.len());
+
+        if self.threads < 2 || chunks.len() < 2 {
+            let mut matches = self.scan(buffer);
+
+            for m in matches.by_ref() {
+                if f(&m).is_break() {
+                    break;
+                }
+            }
+
+            return matches.state.into_stats();
+        }
+
+        let next_chunk = AtomicUsize::new(0);
+        // Offsets below this are covered by the streams merged so far
+        let covered = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+
+            for _ in 0..self.threads.min(chunks.len()) {
+                let tx = tx.clone();
+                let (chunks, next_chunk, covered, stop) = (&chunks, &next_chunk, &covered, &stop);
+
+                scope.spawn(move || {
+                    while let Some(chunk) = chunks.get(next_chunk.fetch_add(1, Ordering::Relaxed)) {
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+
+                        let result = self.scan_chunk(buffer, chunk.clone(), covered, stop);
+
+                        if tx.send((chunk.start, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+
+            drop(tx);
+
+            let mut state = DetectState::new(buffer, &self.detect_options);
+            let mut pending = BTreeMap::new();
+            let mut chunks = chunks.iter();
+            let mut next = chunks.next();
+
+            'merge: for (start, result) in rx {
+                pending.insert(start, result);
+
+                while let Some(chunk) = next {
+                    let Some(result) = pending.remove(&chunk.start) else {
+                        break;
+                    };
+
+                    if self
+                        .merge_chunk(&mut state, chunk.clone(), result, &mut f)
+                        .is_break()
+                    {
+                        stop.store(true, Ordering::Relaxed);
+                        break 'merge;
+                    }
+
+                    covered.store(state.end.map_or(0, |x| x + 1), Ordering::Relaxed);
+                    next = chunks.next();
+                }
+            }
+
+            state.into_stats()
+        })
+    }
+
+    /// Scans an input that cannot be memory-mapped (stdin, a pipe, a file
+    /// larger than the address space) through a sliding window and calls
+    /// `f` for every match with the stream bytes, in ascending offset order.
+    ///
+    /// The reader keeps `2 * window_size` bytes in memory and runs the
+    /// detectors on the candidates in the first half, so every candidate
+    /// sees at least `window_size` bytes: longer streams are cut at that
+    /// size (see [`ScanStats::truncated_streams`]). The scan runs on the
+    /// calling thread.
+    pub fn scan_reader<R, F>(
+        &self,
+        mut reader: R,
+        window_size: usize,
+        mut f: F,
+    ) -> Result<ScanStats>
+    where
+        R: Read,
+        F: FnMut(&StreamMatch, &[u8]) -> ControlFlow<()>,
+    {
+        let window_size = window_size.max(MIN_WINDOW_SIZE);
+        let mut buffer = Vec::with_capacity(2 * window_size);
+        let mut stats = ScanStats::default();
+        let mut detector_stats = StatsTable::default();
+        // Absolute offset of `buffer[0]`
+        let mut base = 0;
+        let mut end: Option<usize> = None;
+
+        loop {
+            let eof = fill_buffer(&mut reader, &mut buffer, 2 * window_size)?;
+            let limit = if eof { buffer.len() } else { window_size };
+            let mut state = DetectState::new(&buffer, &self.detect_options);
+            let mut flow = ControlFlow::Continue(());
+
+            if let Some(covered) = end.filter(|&x| x >= base).map(|x| x - base) {
+                state.processed_sectors.ranges_insert(0..=covered);
+                state.end = Some(covered);
+            }
+
+            for (offset, stream_types) in self.candidates(&buffer, 0..limit) {
+                let Some(mut m) = state.detect(offset, stream_types) else {
+                    continue;
+                };
+
+                if !eof && m.offset + m.size == buffer.len() {
+                    stats.truncated_streams += 1;
+                }
+
+                let bytes = &buffer[m.offset..m.offset + m.size];
+                m.offset += base;
+
+                stats
+                    .processed_sectors
+                    .ranges_insert(m.offset..=(m.offset + m.size - 1));
+                end = end.max(Some(m.offset + m.size - 1));
+
+                flow = f(&m, bytes);
+
+                if flow.is_break() {
+                    break;
+                }
+            }
+
+            add_stats(&mut detector_stats, &state.stats, &StatsTable::default());
+            stats.scanned_bytes = base + buffer.len();
+
+            if eof || flow.is_break() {
+                break;
+            }
+
+            buffer.drain(..window_size);
+            base += window_size;
+        }
+
+        stats.detector_stats = detector_stats_map(&detector_stats);
+
+        Ok(stats)
+    }
+
+    /// Memory-maps the file and collects all matches.
+    pub fn scan_file(&self, file: &File) -> Result<Vec<StreamMatch>> {
+        let mmap = unsafe { Mmap::map(file).map_err(Error::Mmap)? };
+        let mut matches = Vec::new();
+
+        self.scan_with(&mmap, |m| {
+            matches.push(m.clone());
+            ControlFlow::Continue(())
+        });
+
+        Ok(matches)
+    }
+
+    fn chunks(&self, len: usize) -> Vec<Range<usize>> {
+        (0..len.div_ceil(self.chunk_size))
+            .map(|i| i * self.chunk_size..((i + 1) * self.chunk_size).min(len))
+            .collect()
+    }
+
+    /// Scans a chunk as if no stream had been found before it. Candidates
+    /// below `covered` are skipped: they are inside a stream found in an
+    /// earlier chunk and would be dropped by the merge anyway.
+    fn scan_chunk(
+        &self,
+        buffer: &[u8],
+        chunk: Range<usize>,
+        covered: &AtomicUsize,
+        stop: &AtomicBool,
+    ) -> ChunkResult {
+        let mut state = DetectState::new(buffer, &self.detect_options);
+        let mut matches = Vec::new();
+        let mut current = None;
+        let mut stats_before = state.stats;
+
+        if chunk.end <= covered.load(Ordering::Relaxed) {
+            return ChunkResult {
+                matches,
+                stats: state.stats,
+            };
+        }
+
+        for (offset, stream_types) in self.candidates(buffer, chunk) {
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+
+            if offset < covered.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            if current != Some(offset) {
+                current = Some(offset);
+                stats_before = state.stats;
+            }
+
+            if let Some(m) = state.detect(offset, stream_types) {
+                matches.push((m, stats_before));
+            }
+        }
+
+        ChunkResult {
+            matches,
+            stats: state.stats,
+        }
+    }
+
+    /// Applies the chunk result to the global state. Candidates that
+    /// were skipped (or not) because of the chunk-local state are
+    /// re-checked until both states agree again.
+    fn merge_chunk<F>(
+        &self,
+        state: &mut DetectState,
+        chunk: Range<usize>,
+        result: ChunkResult,
+        f: &mut F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&StreamMatch) -> ControlFlow<()>,
+    {
+        let mut next = 0;
+
+        if let Some(start) = state.end.map(|x| x + 1).filter(|&x| x > chunk.start) {
+            // Last byte covered by the chunk-local matches before `next`
+            let mut chunk_end: Option<usize> = None;
+            let mut synced = false;
+
+            let candidates = self.candidates(state.buffer, start.min(chunk.end)..chunk.end);
+
+            for (offset, stream_types) in candidates {
+                while let Some((m, _)) = result.matches.get(next).filter(|(m, _)| m.offset < offset)
+                {
+                    chunk_end = chunk_end.max(Some(m.offset + m.size - 1));
+                    next += 1;
+                }
+
+                if let Some((m, stats_before)) = result.matches.get(next) {
+                    let covered = |end: Option<usize>| end.is_some_and(|x| x >= offset);
+
+                    if m.offset == offset && !covered(state.end) && !covered(chunk_end) {
+                        add_stats(&mut state.stats, &result.stats, stats_before);
+                        synced = true;
+                        break;
+                    }
+                }
+
+                if let Some(m) = state.detect(offset, stream_types) {
+                    f(&m)?;
+                }
+            }
+
+            if !synced {
+                return ControlFlow::Continue(());
+            }
+        } else {
+            add_stats(&mut state.stats, &result.stats, &StatsTable::default());
+        }
+
+        for (m, _) in &result.matches[next..] {
+            state.insert(m);
+            f(m)?;
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Candidate offsets within `range`. The search window extends past the
+    /// range by the longest pattern minus one byte, so a pattern starting
+    /// inside the range is found even if it crosses the boundary; matches
+    /// starting in the overlap belong to the next range. Patterns with a
+    /// lookbehind are searched `lookbehind` bytes further.
+    fn candidates<'a>(&'a self, buffer: &'a [u8], range: Range<usize>) -> Candidates<'a> {
+        let mut sources: Vec<Box<dyn Iterator<Item = Candidate<'a>> + 'a>> = Vec::new();
+
+        for set in &self.pattern_sets {
+            let overlap = set.ac.max_pattern_len() - 1;
+            let start = (range.start + set.lookbehind).min(buffer.len());
+            let span = start..(range.end + set.lookbehind + overlap).clamp(start, buffer.len());
+            let end = range.end + set.lookbehind;
+
+            sources.push(Box::new(
+                set.ac
+                    .find_iter(Input::new(buffer).span(span))
+                    .take_while(move |c| c.start() < end)
+                    .map(|c| {
+                        let (_, stream_types) = &set.patterns[c.pattern()];
+                        (c.start() - set.lookbehind, stream_types.as_slice())
+                    }),
+            ));
+        }
+
+        for (pattern, stream_types) in &self.byte1_patterns {
+            let start = range.start;
+
+            sources.push(Box::new(
+                memchr::memchr_iter(pattern[0], &buffer[range.clone()])
+                    .map(move |x| (start + x, stream_types.as_slice())),
+            ));
+        }
+
+        Candidates {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+/// Reads until the buffer holds `size` bytes. Returns `true` at the end
+/// of the input.
+fn fill_buffer(reader: &mut impl Read, buffer: &mut Vec<u8>, size: usize) -> Result<bool> {
+    let missing = size.saturating_sub(buffer.len());
+    let bytes_read = reader.take(missing as u64).read_to_end(buffer)?;
+
+    Ok(bytes_read < missing)
+}
+
+fn detector_stats_map(stats: &StatsTable) -> BTreeMap<StreamType, DetectorStats> {
+    StreamType::ALL
+        .into_iter()
+        .map(|x| (x, stats[x as usize]))
+        .filter(|(_, stats)| stats.candidates > 0)
+        .collect()
+}
+
+/// Adds the counters gathered between `before` and `after` to `total`.
+fn add_stats(total: &mut StatsTable, after: &StatsTable, before: &StatsTable) {
+    for (total, (after, before)) in total.iter_mut().zip(after.iter().zip(before)) {
+        total.candidates += after.candidates - before.candidates;
+        total.accepted += after.accepted - before.accepted;
+    }
+}
+
+/// Merges candidate offsets of all patterns in ascending order.
+/// On equal offsets multi-byte patterns come first.
+struct Candidates<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item = Candidate<'a>> + 'a>>>,
+}
+
+impl<'a> Iterator for Candidates<'a> {
+    type Item = Candidate<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<(usize, usize)> = None;
+
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            if let Some(&(offset, _)) = source.peek() {
+                if next.is_none_or(|(_, x)| offset < x) {
+                    next = Some((i, offset));
+                }
+            }
+        }
+
+        next.and_then(|(i, _)| self.sources[i].next())
+    }
+}
+
+/// How many candidate offsets a detector looked at and how many of them
+/// it accepted as a stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DetectorStats {
+    pub candidates: u64,
+    pub accepted: u64,
+}
+
+/// Totals of a finished [`Scanner::scan_with`] or [`Scanner::scan_reader`] run.
+#[derive(Debug, Clone, Default)]
+pub struct ScanStats {
+    /// Byte ranges covered by the found streams
+    pub processed_sectors: RangeSetBlaze<usize>,
+    pub detector_stats: BTreeMap<StreamType, DetectorStats>,
+    pub scanned_bytes: usize,
+    /// Streams that reached the end of the window and may be cut
+    /// (`scan_reader` only)
+    pub truncated_streams: usize,
+}
+
+/// Found streams of a chunk together with the detector stats taken
+/// before the first candidate at their offset.
+struct ChunkResult {
+    matches: Vec<(StreamMatch, StatsTable)>,
+    stats: StatsTable,
+}
+
+/// Sectors covered so far and the detector counters.
+struct DetectState<'a> {
+    buffer: &'a [u8],
+    detect_options: &'a DetectOptions,
+    processed_sectors: RangeSetBlaze<usize>,
+    /// Last byte covered by the found streams
+    end: Option<usize>,
+    stats: StatsTable,
+}
+
+impl<'a> DetectState<'a> {
+    fn new(buffer: &'a [u8], detect_options: &'a DetectOptions) -> Self {
+        DetectState {
+            buffer,
+            detect_options,
+            processed_sectors: RangeSetBlaze::new(),
+            end: None,
+            stats: StatsTable::default(),
+        }
+    }
+
+    fn detect(&mut self, offset: usize, stream_types: &[StreamType]) -> Option<StreamMatch> {
+        for &stream_type in stream_types {
+            if self.processed_sectors.contains(offset) {
+                return None;
+            }
+
+            let detector = stream_type.detector();
+            let stats = &mut self.stats[stream_type as usize];
+            stats.candidates += 1;
+
+            if let Some(m) = detector.detect(self.buffer, offset, self.detect_options) {
+                stats.accepted += 1;
+                self.insert(&m);
+
+                return Some(m);
+            }
+        }
+
+        None
+    }
+
+    fn insert(&mut self, m: &StreamMatch) {
+        let end = m.offset + m.size - 1;
+
+        self.processed_sectors.ranges_insert(m.offset..=end);
+        self.end = self.end.max(Some(end));
+    }
+
+    fn into_stats(self) -> ScanStats {
+        ScanStats {
+            detector_stats: detector_stats_map(&self.stats),
+            processed_sectors: self.processed_sectors,
+            scanned_bytes: self.buffer.len(),
+            truncated_streams: 0,
+        }
+    }
+}
+
+/// Iterator over the streams found by [`Scanner::scan`].
+pub struct Matches<'a> {
+    state: DetectState<'a>,
+    candidates: Candidates<'a>,
+}
+
+impl Matches<'_> {
+    /// Byte ranges covered by the streams found so far.
+    pub fn processed_sectors(&self) -> &RangeSetBlaze<usize> {
+        &self.state.processed_sectors
+    }
+
+    /// Candidate/accepted counters of the detectors run so far.
+    pub fn detector_stats(&self) -> BTreeMap<StreamType, DetectorStats> {
+        detector_stats_map(&self.state.stats)
+    }
+}
+
+impl Iterator for Matches<'_> {
+    type Item = StreamMatch;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (offset, stream_types) in self.candidates.by_ref() {
+            if let Some(m) = self.state.detect(offset, stream_types) {
+                return Some(m);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 8 kHz mono 8-bit PCM WAVE file with `data` as the audio.
+    fn wav(data: &[u8]) -> Vec<u8> {
+        [
+            b"RIFF".as_slice(),
+            &(36 + data.len() as u32).to_le_bytes(),
+            b"WAVEfmt ",
+            &[16, 0, 0, 0, 1, 0, 1, 0],
+            &8000u32.to_le_bytes(),
+            &8000u32.to_le_bytes(),
+            &[1, 0, 8, 0],
+            b"data",
+            &(data.len() as u32).to_le_bytes(),
+            data,
+        ]
+        .concat()
+    }
+
+    /// Zero-filled buffer of `len` bytes with the streams at their offsets
+    /// (cut at the end of the buffer).
+    fn place(len: usize, streams: &[(usize, Vec<u8>)]) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+
+        for (offset, stream) in streams {
+            let end = len.min(offset + stream.len());
+            buffer[*offset..end].copy_from_slice(&stream[..end - offset]);
+        }
+
+        buffer
+    }
+
+    fn spans(matches: impl IntoIterator<Item = StreamMatch>) -> Vec<(usize, usize)> {
+        matches.into_iter().map(|m| (m.offset, m.size)).collect()
+    }
+
+    /// 6 MiB buffer with streams of up to 1.5 MiB at pseudo-random offsets,
+    /// some of them nested in others or crossing chunk boundaries.
+    fn random_streams() -> Vec<u8> {
+        let mut buffer = vec![0; 6 * MIN_CHUNK_SIZE];
+        let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut random = |max: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) as usize % max
+        };
+        let mut offset = random(1000);
+
+        while offset < buffer.len() {
+            let size = match random(10) {
+                0 => random(3 * MIN_CHUNK_SIZE / 2),
+                _ => random(200_000),
+            };
+            let stream = wav(&vec![0x80; size]);
+            let end = buffer.len().min(offset + stream.len());
+
+            buffer[offset..end].copy_from_slice(&stream[..end - offset]);
+            offset += random(300_000);
+        }
+
+        buffer
+    }
+
+    /// Matches and stats of `scan_with` on `threads` threads.
+    fn scan_all(buffer: &[u8], threads: usize) -> (Vec<(usize, usize)>, ScanStats) {
+        let scanner = Scanner::builder()
+            .threads(threads)
+            .chunk_size(MIN_CHUNK_SIZE)
+            .build()
+            .unwrap();
+        let mut matches = Vec::new();
+
+        let stats = scanner.scan_with(buffer, |m| {
+            matches.push(m.clone());
+            ControlFlow::Continue(())
+        });
+
+        (spans(matches), stats)
+    }
+
+    #[test]
+    fn scan_finds_streams_in_order() {
+        let nested = place(2000, &[(500, wav(&[0x80; 300]))]);
+        let buffer = place(
+            10_000,
+            &[
+                (6000, wav(&[0x80; 1000])),
+                (100, wav(&[0x80; 1000])),
+                (2000, wav(&nested)),
+            ],
+        );
+
+        let scanner = Scanner::builder().build().unwrap();
+        let mut matches = scanner.scan(&buffer);
+
+        assert_eq!(
+            spans(matches.by_ref()),
+            [(100, 1044), (2000, 2044), (6000, 1044)]
+        );
+        assert_eq!(matches.processed_sectors().ranges_len(), 3);
+        assert!(matches.processed_sectors().contains(2000 + 44 + 500));
+
+        // Only the enabled stream types are detected
+        let scanner = Scanner::builder()
+            .stream_types(&[StreamType::Ogg, StreamType::Png])
+            .build()
+            .unwrap();
+
+        assert_eq!(scanner.scan(&buffer).count(), 0);
+    }
+
+    #[test]
+    fn detector_stats_count_candidates() {
+        let nested = place(2000, &[(500, wav(&[0x80; 300]))]);
+        let buffer = place(
+            10_000,
+            &[
+                (100, wav(&[0x80; 1000])),
+                (2000, wav(&nested)),
+                (8000, b"RIFF\x04\0\0\0JUNK".to_vec()),
+            ],
+        );
+
+        let scanner = Scanner::builder().build().unwrap();
+        let stats = scanner.scan_with(&buffer, |_| ControlFlow::Continue(()));
+
+        // The nested stream is not a candidate, the last one is rejected
+        // by both RIFF detectors
+        assert_eq!(
+            stats.detector_stats,
+            BTreeMap::from([
+                (
+                    StreamType::RiffWave,
+                    DetectorStats {
+                        candidates: 3,
+                        accepted: 2
+                    }
+                ),
+                (
+                    StreamType::Riff,
+                    DetectorStats {
+                        candidates: 1,
+                        accepted: 0
+                    }
+                ),
+            ])
+        );
+        assert_eq!(stats.scanned_bytes, 10_000);
+        assert_eq!(stats.processed_sectors.len(), 1044 + 2044);
+    }
+
+    #[test]
+    fn parallel_scan_matches_sequential_scan() {
+        let buffer = random_streams();
+        let (matches, stats) = scan_all(&buffer, 1);
+
+        assert!(matches.len() > 10);
+        assert_eq!(
+            spans(Scanner::builder().build().unwrap().scan(&buffer)),
+            matches
+        );
+
+        for threads in [2, 3, 8] {
+            let (parallel_matches, parallel_stats) = scan_all(&buffer, threads);
+
+            assert_eq!(parallel_matches, matches, "{} threads", threads);
+            assert_eq!(parallel_stats.detector_stats, stats.detector_stats);
+            assert_eq!(parallel_stats.processed_sectors, stats.processed_sectors);
+            assert_eq!(parallel_stats.scanned_bytes, stats.scanned_bytes);
+        }
+    }
+
+    #[test]
+    fn parallel_scan_stops_on_break() {
+        let buffer = random_streams();
+        let (matches, _) = scan_all(&buffer, 1);
+
+        let scanner = Scanner::builder()
+            .threads(4)
+            .chunk_size(MIN_CHUNK_SIZE)
+            .build()
+            .unwrap();
+        let mut found = Vec::new();
+
+        scanner.scan_with(&buffer, |m| {
+            found.push((m.offset, m.size));
+
+            match found.len() {
+                5 => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+
+        assert_eq!(found, matches[..5]);
+    }
+
+    #[test]
+    fn candidates_are_merged_in_order() {
+        let scanner = Scanner::builder().build().unwrap();
+        let buffer = place(
+            100,
+            &[
+                (10, b"RIFF".to_vec()),
+                (16, vec![0xFF]),
+                (20, b"OggS".to_vec()),
+                (40, crate::detector::jpeg::SIGNATURE.to_vec()),
+                (60, b"BM".to_vec()),
+                (70, b"xxxxftyp".to_vec()),
+            ],
+        );
+        let offsets = |range: Range<usize>| -> Vec<(usize, Vec<StreamType>)> {
+            scanner
+                .candidates(&buffer, range)
+                .map(|(offset, stream_types)| (offset, stream_types.to_vec()))
+                .collect()
+        };
+
+        // Multi-byte patterns come first on equal offsets
+        assert_eq!(
+            offsets(0..100),
+            [
+                (10, vec![StreamType::RiffWave, StreamType::Riff]),
+                (16, vec![StreamType::Aac, StreamType::Mp3]),
+                (20, vec![StreamType::Ogg]),
+                (40, vec![StreamType::Jpeg]),
+                (40, vec![StreamType::Aac, StreamType::Mp3]),
+                (42, vec![StreamType::Aac, StreamType::Mp3]),
+                (60, vec![StreamType::Bitmap]),
+                (70, vec![StreamType::IsoBmff]),
+            ]
+        );
+
+        // A pattern belongs to the range it starts in, even when it
+        // crosses the end of the range
+        let first: Vec<_> = offsets(0..12).into_iter().map(|(x, _)| x).collect();
+        let second: Vec<_> = offsets(12..72).into_iter().map(|(x, _)| x).collect();
+
+        assert_eq!(first, [10]);
+        assert_eq!(second, [16, 20, 40, 40, 42, 60, 70]);
+    }
+
+    #[test]
+    fn streams_across_chunk_boundaries() {
+        const CHUNK: usize = MIN_CHUNK_SIZE;
+
+        // A stream crossing into the second chunk holds the header of
+        // another one, which runs past it and hides a third stream from
+        // the chunk-local scan
+        let buffer = place(
+            3 * CHUNK,
+            &[
+                (CHUNK - 1000, wav(&[0x80; 5000])),
+                (CHUNK + 1000, wav(&[0x80; 20_000])),
+                (CHUNK + 8000, wav(&[0x80; 1000])),
+                (CHUNK + 30_000, wav(&[0x80; 1000])),
+                (2 * CHUNK - 10, wav(&vec![0x80; CHUNK + 1000])),
+                (2 * CHUNK + 500, wav(&[0x80; 1000])),
+            ],
+        );
+
+        let expected = [
+            (CHUNK - 1000, 5044),
+            (CHUNK + 8000, 1044),
+            (CHUNK + 30_000, 1044),
+            (2 * CHUNK - 10, CHUNK + 10),
+        ];
+
+        let sequential = Scanner::builder().build().unwrap();
+        assert_eq!(spans(sequential.scan(&buffer)), expected);
+
+        for threads in [2, 3] {
+            let (matches, stats) = scan_all(&buffer, threads);
+            let (_, sequential_stats) = scan_all(&buffer, 1);
+
+            assert_eq!(matches, expected, "{} threads", threads);
+            assert_eq!(stats.detector_stats, sequential_stats.detector_stats);
+        }
+    }
+
+    #[test]
+    fn reader_windows_carry_the_covered_range() {
+        const WINDOW: usize = MIN_WINDOW_SIZE;
+
+        let buffer = place(
+            7 * WINDOW / 2,
+            &[
+                (100, wav(&[0x80; 1000])),
+                (WINDOW - 500, wav(&[0x80; 3000])),
+                (WINDOW + 1000, wav(&[0x80; 500_000])),
+                (WINDOW + 2000, wav(&[0x80; 100])),
+                (2 * WINDOW - 100, wav(&[0x80; 1000])),
+                (3 * WINDOW + 10, wav(&[0x80; 1000])),
+            ],
+        );
+
+        let scanner = Scanner::builder().build().unwrap();
+        let mut matches = Vec::new();
+
+        let stats = scanner
+            .scan_reader(buffer.as_slice(), WINDOW, |m, bytes| {
+                assert_eq!(bytes, &buffer[m.offset..m.offset + m.size]);
+                matches.push(m.clone());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+
+        assert_eq!(spans(matches), spans(scanner.scan(&buffer)));
+        assert_eq!(stats.truncated_streams, 0);
+        assert_eq!(stats.scanned_bytes, buffer.len());
+        assert_eq!(
+            stats.detector_stats,
+            scanner
+                .scan_with(&buffer, |_| ControlFlow::Continue(()))
+                .detector_stats
+        );
+    }
+
+    #[test]
+    fn reader_cuts_streams_longer_than_the_window() {
+        const WINDOW: usize = MIN_WINDOW_SIZE;
+
+        // The second window holds the first 1.2 MiB of the long stream,
+        // the third one starts inside it
+        let buffer = place(
+            4 * WINDOW,
+            &[
+                (WINDOW * 9 / 5, wav(&vec![0x80; WINDOW * 3 / 2])),
+                (WINDOW * 5 / 2, wav(&[0x80; 1000])),
+                (WINDOW * 7 / 2, wav(&[0x80; 1000])),
+            ],
+        );
+
+        let scanner = Scanner::builder().build().unwrap();
+        let mut matches = Vec::new();
+
+        let stats = scanner
+            .scan_reader(buffer.as_slice(), WINDOW, |m, _| {
+                matches.push(m.clone());
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            spans(matches),
+            [
+                (WINDOW * 9 / 5, 3 * WINDOW - WINDOW * 9 / 5),
+                (WINDOW * 7 / 2, 1044)
+            ]
+        );
+        assert_eq!(stats.truncated_streams, 1);
+        assert_eq!(stats.scanned_bytes, buffer.len());
+    }
+}
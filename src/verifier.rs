@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::fs::{read_dir, File};
+use std::path::Path;
+
+use crate::error::Result;
+use crate::manifest::{hash_file, hash_range, Manifest, ManifestEntry};
+
+/// Outcome of [`verify`] for every stream of the manifest.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub ok: usize,
+    pub mismatched: usize,
+    pub modified: usize,
+    pub missing: usize,
+    pub extra: usize,
+    /// Whether the whole file hashes to the original again (only known
+    /// when sectors were erased)
+    pub file_restored: Option<bool>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched == 0
+            && self.modified == 0
+            && self.missing == 0
+            && self.extra == 0
+            && self.file_restored != Some(false)
+    }
+}
+
+/// Progress of [`verify`].
+pub enum VerifyEvent<'a> {
+    /// The file size differs from the one recorded in the manifest
+    SizeMismatch { size: u64, expected: u64 },
+    /// The stream bytes in the file match the stream file
+    Matched(&'a ManifestEntry),
+    /// The stream bytes in the file differ from the stream file
+    Mismatched(&'a ManifestEntry),
+    /// The stream file differs from the extracted stream
+    Modified(&'a ManifestEntry, &'a Path),
+    /// The stream file is missing
+    Missing(&'a ManifestEntry),
+    /// A file in the folder that the manifest does not list
+    Extra(&'a Path),
+}
+
+/// Checks the streams listed in the manifest of `input_dir` against their
+/// files and against the bytes at their offsets in `file_path`.
+pub fn verify(
+    file_path: &Path,
+    input_dir: &Path,
+    mut on_event: impl FnMut(VerifyEvent),
+) -> Result<VerifyReport> {
+    let manifest = Manifest::read(input_dir)?;
+    let src = File::open(file_path)?;
+    let src_size = src.metadata()?.len();
+
+    let mut report = VerifyReport::default();
+    let mut known_files = HashSet::from([Manifest::path(input_dir)]);
+
+    if src_size != manifest.source.size {
+        on_event(VerifyEvent::SizeMismatch {
+            size: src_size,
+            expected: manifest.source.size,
+        });
+    }
+
+    for entry in &manifest.streams {
+        let path = match entry.resolve(input_dir) {
+            Ok(Some(path)) => path,
+            _ => {
+                report.missing += 1;
+                on_event(VerifyEvent::Missing(entry));
+                continue;
+            }
+        };
+
+        let file_hash = File::open(&path).and_then(|f| hash_file(&f))?;
+
+        if file_hash != entry.sha256 {
+            report.modified += 1;
+            on_event(VerifyEvent::Modified(entry, &path));
+            known_files.insert(path);
+            continue;
+        }
+
+        known_files.insert(path);
+
+        match hash_range(&src, entry.offset, entry.size) {
+            Ok(hash) if hash == entry.sha256 => {
+                report.ok += 1;
+                on_event(VerifyEvent::Matched(entry));
+            }
+            _ => {
+                report.mismatched += 1;
+                on_event(VerifyEvent::Mismatched(entry));
+            }
+        }
+    }
+
+    for dir_entry in read_dir(input_dir)? {
+        let path = dir_entry?.path();
+
+        if path.is_file() && !known_files.contains(&path) {
+            report.extra += 1;
+            on_event(VerifyEvent::Extra(&path));
+        }
+    }
+
+    if let Some(expected) = &manifest.source.sha256 {
+        let hash = hash_file(&src)?;
+        report.file_restored = Some(&hash == expected);
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,278 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// Creates an empty directory for a test under the system temp dir.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mpsd-cli-{}-{}", name, std::process::id()));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn mpsd(args: &[&str], dir: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mpsd"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// 8 kHz mono 8-bit PCM WAVE file with `samples` bytes of audio.
+fn wav(samples: usize) -> Vec<u8> {
+    [
+        b"RIFF".as_slice(),
+        &(36 + samples as u32).to_le_bytes(),
+        b"WAVEfmt ",
+        &16u32.to_le_bytes(),
+        &1u16.to_le_bytes(),
+        &1u16.to_le_bytes(),
+        &8000u32.to_le_bytes(),
+        &8000u32.to_le_bytes(),
+        &1u16.to_le_bytes(),
+        &8u16.to_le_bytes(),
+        b"data",
+        &(samples as u32).to_le_bytes(),
+        &vec![0x80; samples],
+    ]
+    .concat()
+}
+
+/// Input with two WAVE streams, at 1000 and 3000.
+fn input() -> Vec<u8> {
+    [
+        vec![0; 1000],
+        wav(1000),
+        vec![0; 956],
+        wav(2000),
+        vec![0; 500],
+    ]
+    .concat()
+}
+
+#[test]
+fn verify_after_extract_and_inject() {
+    let dir = temp_dir("verify");
+    fs::write(dir.join("in.bin"), input()).unwrap();
+
+    let output = mpsd(&["extract", "in.bin", "out"], &dir);
+    assert!(output.status.success());
+    assert!(dir.join("out/1000.wav").is_file());
+    assert!(dir.join("out/3000.wav").is_file());
+
+    let output = mpsd(&["verify", "in.bin", "out"], &dir);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("-> Matched streams: 2"));
+
+    // A changed stream file
+    fs::write(dir.join("out/3000.wav"), wav(2000).repeat(2)).unwrap();
+
+    let output = mpsd(&["verify", "in.bin", "out"], &dir);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stdout(&output).contains("-> Modified stream files: 1"));
+
+    // A changed input file
+    fs::write(dir.join("out/3000.wav"), wav(2000)).unwrap();
+    fs::write(dir.join("in.bin"), [vec![0; 1000], wav(1001)].concat()).unwrap();
+
+    let output = mpsd(&["verify", "in.bin", "out"], &dir);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stdout(&output).contains("-> Mismatched streams: 2"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unerase_restores_the_erased_sectors() {
+    let dir = temp_dir("unerase");
+    let input = input();
+    fs::write(dir.join("in.bin"), &input).unwrap();
+
+    let output = mpsd(&["scan", "--erase-sectors", "in.bin"], &dir);
+    assert!(output.status.success());
+    assert!(dir.join("in.bin.mpsd-journal").is_file());
+
+    let erased = fs::read(dir.join("in.bin")).unwrap();
+    assert_eq!(erased.len(), input.len());
+    assert!(erased.iter().all(|&b| b == 0));
+
+    // The journal is not overwritten by another erase
+    let output = mpsd(&["scan", "--erase-sectors", "in.bin"], &dir);
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = mpsd(&["unerase", "in.bin"], &dir);
+    assert!(output.status.success());
+    assert_eq!(fs::read(dir.join("in.bin")).unwrap(), input);
+    assert!(!dir.join("in.bin.mpsd-journal").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn inject_after_erase_removes_the_journal() {
+    let dir = temp_dir("inject");
+    let input = input();
+    fs::write(dir.join("in.bin"), &input).unwrap();
+
+    let output = mpsd(&["extract", "--erase-sectors", "in.bin", "out"], &dir);
+    assert!(output.status.success());
+    assert!(dir.join("in.bin.mpsd-journal").is_file());
+
+    let output = mpsd(&["inject", "in.bin", "out"], &dir);
+    assert!(output.status.success());
+    assert_eq!(fs::read(dir.join("in.bin")).unwrap(), input);
+    assert!(!dir.join("in.bin.mpsd-journal").exists());
+
+    let output = mpsd(&["verify", "in.bin", "out"], &dir);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("restored"));
+
+    // The file can be erased again
+    let output = mpsd(&["extract", "--erase-sectors", "in.bin", "out"], &dir);
+    assert!(output.status.success());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn exit_codes() {
+    let dir = temp_dir("exit-codes");
+    fs::write(dir.join("in.bin"), input()).unwrap();
+    fs::write(dir.join("empty.bin"), vec![0; 1000]).unwrap();
+
+    let code = |args: &[&str]| mpsd(args, &dir).status.code();
+
+    assert_eq!(code(&["scan", "in.bin"]), Some(0));
+    assert_eq!(code(&["scan", "empty.bin"]), Some(1));
+    assert_eq!(code(&["scan", "--bmp-strict", "3", "in.bin"]), Some(2));
+    assert_eq!(code(&["scan", "missing.bin"]), Some(4));
+    assert_eq!(code(&["inject", "in.bin", "missing"]), Some(7));
+    assert_eq!(code(&["unerase", "in.bin"]), Some(7));
+
+    // The input does not match the manifest
+    assert_eq!(code(&["extract", "in.bin", "out"]), Some(0));
+    assert_eq!(code(&["inject", "empty.bin", "out"]), Some(3));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn extract_to_a_full_disk() {
+    let dir = temp_dir("full-disk");
+    fs::write(dir.join("in.bin"), input()).unwrap();
+
+    // Writes to the first stream file fail with ENOSPC
+    fs::create_dir(dir.join("out")).unwrap();
+    std::os::unix::fs::symlink("/dev/full", dir.join("out").join("1000.wav")).unwrap();
+
+    let output = mpsd(&["extract", "in.bin", "out"], &dir);
+    assert_eq!(output.status.code(), Some(5));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Error:"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn output_formats() {
+    let dir = temp_dir("formats");
+    fs::write(dir.join("in.bin"), input()).unwrap();
+
+    // JSON Lines: the streams, then the summary
+    let output = mpsd(&["scan", "--format", "jsonl", "in.bin"], &dir);
+    let records: Vec<serde_json::Value> = stdout(&output)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["record"], "stream");
+    assert_eq!(records[0]["offset"], 1000);
+    assert_eq!(records[0]["size"], 1044);
+    assert_eq!(records[0]["type"], "RiffWave");
+    assert_eq!(records[0]["ext"], "wav");
+    assert_eq!(records[0]["metadata"]["sample_rate"], 8000);
+    assert_eq!(records[1]["offset"], 3000);
+    assert_eq!(records[2]["record"], "summary");
+    assert_eq!(records[2]["total_streams_count"], 2);
+    assert_eq!(records[2]["by_type"]["RiffWave"]["count"], 2);
+
+    // --silent only mutes the text log
+    let silent = mpsd(&["-s", "scan", "--format", "jsonl", "in.bin"], &dir);
+    assert_eq!(stdout(&silent).lines().count(), 3);
+
+    // JSON: a single document
+    let output = mpsd(&["-s", "scan", "--format", "json", "in.bin"], &dir);
+    let document: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+
+    assert_eq!(document["streams"].as_array().unwrap().len(), 2);
+    assert_eq!(document["streams"][1]["size"], 2044);
+    assert_eq!(document["summary"]["total_streams_size"], 3088);
+
+    // CSV: a header, the streams, one row per format and the summary
+    let output = mpsd(&["scan", "--format", "csv", "in.bin"], &dir);
+    let csv = stdout(&output);
+    let rows: Vec<&str> = csv.lines().collect();
+
+    assert_eq!(rows[0], "record,offset,size,type,ext,metadata");
+    assert!(rows[1].starts_with("stream,1000,1044,RiffWave,wav,"));
+    assert!(rows[1].contains("format=pcm"));
+    assert!(rows[2].starts_with("stream,3000,2044,RiffWave,wav,"));
+    assert!(rows
+        .iter()
+        .any(|x| x.starts_with("format,,3088,RiffWave,,")));
+    assert!(rows.last().unwrap().starts_with("summary,,3088,,,"));
+
+    // Text
+    let output = mpsd(&["scan", "in.bin"], &dir);
+    assert!(stdout(&output).contains("--> Found RiffWave stream @ 3000 (2044 bytes)"));
+
+    let output = mpsd(&["-s", "scan", "in.bin"], &dir);
+    assert!(!stdout(&output).contains("--> Found"));
+    assert!(stdout(&output).contains("-> Found streams: 2"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn scan_and_extract_from_stdin() {
+    let dir = temp_dir("stdin");
+    let input = input();
+    fs::write(dir.join("in.bin"), &input).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mpsd"))
+        .args(["-s", "extract", "--format", "jsonl", "-", "out"])
+        .current_dir(&dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let from_file = mpsd(&["-s", "scan", "--format", "jsonl", "in.bin"], &dir);
+    let streams = |output: &Output| -> Vec<String> {
+        stdout(output)
+            .lines()
+            .filter(|x| x.contains("\"record\":\"stream\""))
+            .map(String::from)
+            .collect()
+    };
+
+    assert_eq!(streams(&output).len(), 2);
+    assert_eq!(streams(&output), streams(&from_file));
+    assert_eq!(
+        fs::read(dir.join("out/1000.wav")).unwrap(),
+        &input[1000..2044]
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}